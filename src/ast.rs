@@ -3,14 +3,35 @@ pub enum PrimaryExpr {
     Bool(BoolLiteral),
     Block(Block),
     DecimalInt(u64),
+    /// `5s`, `10m` and the like.
+    Duration(crate::time::Duration),
     Identifier(String),
     StringLiteral(String),
+    None,
     TaggedString(TaggedString),
-    Compound(std::collections::BTreeMap<String, Expr>),
+    Compound(Vec<CompoundEntry>),
+    List(Vec<Expr>),
 }
+
+#[derive(Debug, Clone)]
+pub enum CompoundEntry {
+    /// `..base` copies every property of `base`.
+    Spread(Expr),
+    /// `key=value`, or `outer.inner=value` to update a nested compound.
+    Field { path: Vec<String>, def: Expr },
+}
+/// A `tag"..."` literal, already validated by the handler for its tag.
 #[derive(Debug, Clone)]
 pub enum TaggedString {
-    Regex(String),
+    Regex(regex::Regex),
+    Json(serde_json::Value),
+    Path(std::path::PathBuf),
+    Url(url::Url),
+    /// `sh"..."`, split into program and arguments.
+    Sh(Vec<String>),
+    /// A literal whose handler, registered with `tagged::register`, built
+    /// its value itself.
+    Value(crate::interpreter::Value),
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -45,10 +66,25 @@ pub enum MulDivOp {
     Div,
 }
 
+#[derive(Debug, Clone)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
 #[derive(Debug, Clone)]
 pub enum BlockElement {
     Expr(Expr),
     Var {
+        pattern: Pattern,
+        def: Expr,
+        mutable: bool,
+    },
+    Assign {
         name: String,
         def: Expr,
     },
@@ -57,20 +93,112 @@ pub enum BlockElement {
         def: FunctionApplication,
     },
     AnonymousFunction(AnonymousFunction),
+    Fn {
+        name: String,
+        function: AnonymousFunction,
+    },
+    Import {
+        path: String,
+        name: String,
+    },
+    /// `export let` or `export fn` at the top level of a module.
+    Export(Box<BlockElement>),
+    /// `defer { ... }` runs when the enclosing block exits, last one first.
+    Defer(Block),
 }
 #[derive(Debug, Clone)]
 pub struct Block(pub Vec<BlockElement>);
 
 #[derive(Debug, Clone)]
 pub struct AnonymousFunction {
-    pub params: Vec<String>,
+    pub params: Vec<Pattern>,
     pub body: Block,
 }
 
+/// The left-hand side of `let` and a function parameter.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Name(String),
+    /// `(key=pattern, other)`; a bare key binds the property to its own name.
+    Compound(std::collections::BTreeMap<String, Pattern>),
+    /// `[first, second, ..rest]`
+    List {
+        items: Vec<Pattern>,
+        rest: Option<String>,
+    },
+    /// `pattern: Type`; the value is checked against the type before binding.
+    Annotated {
+        pattern: Box<Pattern>,
+        ty: TypeExpr,
+    },
+}
+
+/// A type annotation such as `Int`, `[String]`, `(content: String)` or `String?`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TypeExpr {
+    /// `Int`, `Bool`, `String`, `Unit`, `None`, `Error`, `Fn` or `Any`.
+    Named(String),
+    List(Box<TypeExpr>),
+    /// `(name: Type, ...)`; values may carry more fields than listed.
+    Compound(Vec<(String, TypeExpr)>),
+    /// `Type?`: the type or none.
+    Optional(Box<TypeExpr>),
+}
+
+impl std::fmt::Display for TypeExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TypeExpr::Named(name) => write!(f, "{name}"),
+            TypeExpr::List(item) => write!(f, "[{item}]"),
+            TypeExpr::Compound(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(k, t)| format!("{k}: {t}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "({fields})")
+            }
+            TypeExpr::Optional(t) => write!(f, "{t}?"),
+        }
+    }
+}
+
+impl Pattern {
+    /// Every name the pattern binds. List items come in source order, but a
+    /// compound pattern's fields come in the order of their keys.
+    pub fn names(&self) -> Vec<&str> {
+        match self {
+            Pattern::Name(name) => vec![name],
+            Pattern::Compound(fields) => fields.values().flat_map(Pattern::names).collect(),
+            Pattern::List { items, rest } => items
+                .iter()
+                .flat_map(Pattern::names)
+                .chain(rest.as_deref())
+                .collect(),
+            Pattern::Annotated { pattern, .. } => pattern.names(),
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct Identifier {
     pub child: Option<Box<Identifier>>,
     pub path: String,
+    /// Reached with `?.`: evaluates to none instead of failing on a none parent.
+    pub safe: bool,
+}
+
+impl Identifier {
+    /// Returns true if this identifier is exactly the dotted path `path`, e.g. `["fs", "cwd"]`.
+    pub fn is(&self, path: &[&str]) -> bool {
+        match path.split_first() {
+            Some((head, rest)) if self.path == *head => match &self.child {
+                Some(child) => child.is(rest),
+                None => rest.is_empty(),
+            },
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -87,11 +215,48 @@ pub struct If {
     pub false_expr: Box<Expr>,
 }
 
+#[derive(Debug, Clone)]
+pub struct Try {
+    pub body: Block,
+    pub catch: Option<Catch>,
+    /// Runs after the body and handler, whether or not they failed.
+    pub finally: Option<Block>,
+}
+
+/// `catch name { handler }`
+#[derive(Debug, Clone)]
+pub struct Catch {
+    pub name: String,
+    pub handler: Block,
+}
+
 #[derive(Debug, Clone)]
 pub enum Expr {
     AddSub(BinOp<AddSubOp>),
     MulDiv(BinOp<MulDivOp>),
+    Compare(BinOp<CompareOp>),
     Primary(PrimaryExpr),
     FunctionApplication(FunctionApplication),
     If(If),
+    Try(Try),
+    /// `expr?` raises `expr` if it evaluates to an error value.
+    Propagate(Box<Expr>),
+    Coalesce(Coalesce),
+    Range(Range),
+}
+
+/// `start..end` or `start..=end`, optionally `--step n`.
+#[derive(Debug, Clone)]
+pub struct Range {
+    pub start: Box<Expr>,
+    pub end: Box<Expr>,
+    pub inclusive: bool,
+    pub step: Option<PrimaryExpr>,
+}
+
+/// `value ?? default` evaluates `default` only if `value` is none.
+#[derive(Debug, Clone)]
+pub struct Coalesce {
+    pub value: Box<Expr>,
+    pub default: Box<Expr>,
 }
@@ -0,0 +1,175 @@
+use crate::interpreter::{EvalResult, Value};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    /// Calls with the latest arguments once no call came for the duration.
+    Debounce(Duration),
+    /// Calls at most once per duration; calls in between are dropped.
+    Throttle(Duration),
+    /// Calls with a list of the collected arguments once `max` have come in,
+    /// or `within` after the first of them.
+    Batch {
+        max: Option<usize>,
+        within: Option<Duration>,
+    },
+}
+
+#[derive(Default)]
+struct State {
+    /// Debounce: the arguments of the latest call.
+    latest: Option<Vec<Value>>,
+    /// Batch: the items collected so far.
+    items: Vec<Value>,
+    /// When a pending debounce or batch is due.
+    deadline: Option<Instant>,
+    /// Throttle: when the function was last called.
+    last_call: Option<Instant>,
+    /// Bumped on every flush, so a timer can tell its batch was already sent.
+    generation: u64,
+}
+
+/// A function wrapped by `debounce`, `throttle` or `batch`. Delayed calls are
+/// made from the event loop of the thread that made the first pending call;
+/// one still pending when that loop stops is made right away.
+#[derive(Clone)]
+pub struct Wrapped(Arc<(Kind, Value, Mutex<State>)>);
+
+impl std::fmt::Debug for Wrapped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self.0 .0)
+    }
+}
+
+impl Wrapped {
+    pub fn new(kind: Kind, f: Value) -> Wrapped {
+        Wrapped(Arc::new((kind, f, Mutex::new(State::default()))))
+    }
+
+    /// Handles a call to the wrapper. Returns the wrapped function's result if
+    /// it was called right away, and none otherwise.
+    pub fn call(&self, arguments: Vec<Value>) -> EvalResult {
+        let (kind, f, state) = &*self.0;
+        let mut state = state.lock().unwrap();
+        match *kind {
+            Kind::Debounce(delay) => {
+                let pending = state.latest.replace(arguments).is_some();
+                state.deadline = Some(Instant::now() + delay);
+                if !pending {
+                    drop(state);
+                    self.flush_on_stop();
+                    self.schedule(0)?;
+                }
+                Ok(Value::None)
+            }
+            Kind::Throttle(window) => {
+                let now = Instant::now();
+                if state.last_call.is_some_and(|last| now - last < window) {
+                    return Ok(Value::None);
+                }
+                state.last_call = Some(now);
+                drop(state);
+                f.try_evaluate_as_fn(arguments)
+            }
+            Kind::Batch { max, within } => {
+                // A call with one argument adds that argument, otherwise the list of them.
+                let item = match <[Value; 1]>::try_from(arguments) {
+                    Ok([item]) => item,
+                    Err(arguments) => Value::List(arguments),
+                };
+                state.items.push(item);
+                if max.is_some_and(|max| state.items.len() >= max) {
+                    let items = std::mem::take(&mut state.items);
+                    state.generation += 1;
+                    drop(state);
+                    return f.try_evaluate_as_fn(vec![Value::List(items)]);
+                }
+                if state.items.len() == 1 {
+                    let generation = state.generation;
+                    if let Some(within) = within {
+                        state.deadline = Some(Instant::now() + within);
+                    }
+                    drop(state);
+                    self.flush_on_stop();
+                    if within.is_some() {
+                        self.schedule(generation)?;
+                    }
+                }
+                Ok(Value::None)
+            }
+        }
+    }
+
+    /// Registers a timer that makes the pending call once it is due.
+    fn schedule(&self, generation: u64) -> Result<(), crate::interpreter::Error> {
+        let wrapped = self.clone();
+        crate::reactor::register(self.0 .1.clone(), move |emitter| {
+            let (guard, stopped) = crate::reactor::stop_flag();
+            std::thread::spawn(move || loop {
+                let (_, _, state) = &*wrapped.0;
+                let Some(deadline) = state.lock().unwrap().deadline else {
+                    return;
+                };
+                if !crate::timer::wait_until(deadline, &stopped) {
+                    return;
+                }
+                if let Some(arguments) = wrapped.take_due(generation) {
+                    if !arguments.is_empty() {
+                        emitter.call(arguments);
+                    }
+                    return;
+                }
+            });
+            Ok(guard)
+        })
+    }
+
+    /// Makes sure a call still pending when the event loop stops isn't lost.
+    fn flush_on_stop(&self) {
+        let wrapped = self.clone();
+        let key = Arc::as_ptr(&self.0) as usize;
+        crate::reactor::on_stop(key, self.0 .1.clone(), move || wrapped.take_pending());
+    }
+
+    /// The arguments for the pending call, if there is one, whether it is due or not.
+    fn take_pending(&self) -> Option<Vec<Value>> {
+        let (kind, _, state) = &*self.0;
+        let mut state = state.lock().unwrap();
+        state.deadline = None;
+        match kind {
+            Kind::Debounce(_) => state.latest.take(),
+            Kind::Batch { .. } if !state.items.is_empty() => {
+                state.generation += 1;
+                Some(vec![Value::List(std::mem::take(&mut state.items))])
+            }
+            _ => None,
+        }
+    }
+
+    /// The arguments for the pending call if it is due, `None` if its
+    /// deadline was pushed back. No arguments means there is nothing to call,
+    /// e.g. because the batch was already sent when it filled up.
+    fn take_due(&self, generation: u64) -> Option<Vec<Value>> {
+        let (kind, _, state) = &*self.0;
+        let mut state = state.lock().unwrap();
+        if matches!(kind, Kind::Batch { .. }) && state.generation != generation {
+            return Some(Vec::new());
+        }
+        if state
+            .deadline
+            .is_some_and(|deadline| Instant::now() < deadline)
+        {
+            return None;
+        }
+        state.deadline = None;
+        Some(match kind {
+            Kind::Debounce(_) => state.latest.take().unwrap_or_default(),
+            Kind::Batch { .. } => {
+                state.generation += 1;
+                vec![Value::List(std::mem::take(&mut state.items))]
+            }
+            Kind::Throttle(_) => Vec::new(),
+        })
+    }
+}
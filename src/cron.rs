@@ -0,0 +1,130 @@
+use crate::time::DateTime;
+
+/// A five-field cron schedule: minute, hour, day of month, month and day of
+/// week. Each field is `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`,
+/// or a comma-separated list of those.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// As in cron, when both day fields are restricted a day matching either one fires.
+    either_day: bool,
+}
+
+/// How far ahead to look before deciding a schedule never fires, e.g. `0 0 30 2 *`.
+const HORIZON_SECS: i64 = 5 * 366 * 24 * 3600;
+
+impl Schedule {
+    pub fn parse(spec: &str) -> Result<Schedule, String> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            return Err(format!("cron schedule {spec:?} needs 5 fields"));
+        };
+        let mut weekdays = field(weekday, 0, 7)?;
+        // Both 0 and 7 are Sunday.
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Schedule {
+            minutes: field(minute, 0, 59)?,
+            hours: field(hour, 0, 23)?,
+            days: field(day, 1, 31)?,
+            months: field(month, 1, 12)?,
+            weekdays,
+            either_day: !day.starts_with('*') && !weekday.starts_with('*'),
+        })
+    }
+
+    /// The first whole minute after `t` that the schedule matches, in `t`'s offset.
+    pub fn next_after(&self, t: &DateTime) -> Option<DateTime> {
+        let start = (t.unix().div_euclid(60) + 1) * 60;
+        let mut secs = start;
+        while secs - start < HORIZON_SECS {
+            let candidate = DateTime::from_unix(secs, t.offset());
+            let get = |name| candidate.field(name).ok().flatten().unwrap_or_default() as u32;
+            let (minute, hour) = (get("minute"), get("hour"));
+            if !self.day_matches(get("day"), get("month"), get("weekday")) {
+                secs += ((24 - hour as i64) * 60 - minute as i64) * 60;
+            } else if !has(self.hours, hour) {
+                secs += (60 - minute as i64) * 60;
+            } else if !has(self.minutes, minute) {
+                secs += 60;
+            } else {
+                return Some(candidate);
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, day: u32, month: u32, weekday: u32) -> bool {
+        let (day, weekday) = (has(self.days, day), has(self.weekdays, weekday));
+        has(self.months, month)
+            && if self.either_day {
+                day || weekday
+            } else {
+                day && weekday
+            }
+    }
+}
+
+fn has(set: u64, n: u32) -> bool {
+    set & (1 << n) != 0
+}
+
+/// Parses one field into a bit set of the values it allows.
+fn field(spec: &str, min: u32, max: u32) -> Result<u64, String> {
+    let invalid = || format!("invalid cron field {spec:?}");
+    let number = |s: &str| match s.parse::<u32>() {
+        Ok(n) if (min..=max).contains(&n) => Ok(n),
+        _ => Err(invalid()),
+    };
+    let mut set = 0;
+    for part in spec.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<u32>().map_err(|_| invalid())?),
+            None => (part, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // `5/15` means from 5 to the end in steps of 15.
+            None if part.contains('/') => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if step == 0 || start > end {
+            return Err(invalid());
+        }
+        for n in (start..=end).step_by(step as usize) {
+            set |= 1 << n;
+        }
+    }
+    Ok(set)
+}
+
+#[test]
+fn test_schedule() {
+    let weekdays_at_9 = Schedule::parse("0 9 * * 1-5").unwrap();
+    // A Friday evening in Tokyo; the next weekday morning is Monday.
+    let t = DateTime::parse_rfc3339("2024-03-01T18:30:00+09:00").unwrap();
+    assert_eq!(
+        weekdays_at_9.next_after(&t).unwrap().to_string(),
+        "2024-03-04T09:00:00+09:00"
+    );
+    let quarter_hours = Schedule::parse("*/15 * * * *").unwrap();
+    assert_eq!(
+        quarter_hours.next_after(&t).unwrap().to_string(),
+        "2024-03-01T18:45:00+09:00"
+    );
+    // The 13th, or any Friday.
+    let either = Schedule::parse("0 0 13 * 5").unwrap();
+    assert_eq!(
+        either.next_after(&t).unwrap().to_string(),
+        "2024-03-08T00:00:00+09:00"
+    );
+    assert_eq!(Schedule::parse("0 0 30 2 *").unwrap().next_after(&t), None);
+    assert!(Schedule::parse("60 * * * *").is_err());
+    assert!(Schedule::parse("* * * *").is_err());
+}
@@ -0,0 +1,122 @@
+use crate::interpreter::{Error, Properties, Value};
+use crate::reactor::{Emitter, Guard};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+/// Largest request body accepted; larger ones get `413 Payload Too Large`.
+const MAX_BODY: usize = 1024 * 1024;
+
+/// Largest request line or header accepted.
+const MAX_LINE: u64 = 8 * 1024;
+
+/// How long a client may take to send each part of its request.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Listens on `host` and `port` and passes every request to the event loop as
+/// a compound of `method`, `path` and `body`. The callback's result is the
+/// response: a string is sent as text, anything else as JSON. Each
+/// connection is read on its own thread, so a slow client holds up no other.
+pub fn serve(host: &str, port: u16, emitter: Emitter) -> Result<Guard, Error> {
+    let listener = TcpListener::bind((host, port))
+        .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+        .map_err(|e| Error::new("http", format!("{host}:{port}: {e}")))?;
+    let emitter = std::sync::Arc::new(emitter);
+    let (guard, stopped) = crate::reactor::stop_flag();
+    std::thread::spawn(move || {
+        while !stopped.load(Ordering::SeqCst) {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    let emitter = emitter.clone();
+                    std::thread::spawn(move || {
+                        if let Err(e) = respond(stream, &emitter) {
+                            eprintln!("http.serve: {e}");
+                        }
+                    });
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    std::thread::sleep(Duration::from_millis(50));
+                }
+                Err(e) => return emitter.fail(Error::new("http", e.to_string())),
+            }
+        }
+    });
+    Ok(guard)
+}
+
+/// Reads one line of the request head, failing if it is too long.
+fn read_line(reader: &mut BufReader<TcpStream>) -> std::io::Result<String> {
+    let mut line = String::new();
+    reader.by_ref().take(MAX_LINE).read_line(&mut line)?;
+    if !line.ends_with('\n') && line.len() as u64 == MAX_LINE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "request line or header too long",
+        ));
+    }
+    Ok(line)
+}
+
+fn respond(stream: TcpStream, emitter: &Emitter) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let line = read_line(&mut reader)?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut length = 0;
+    loop {
+        let header = read_line(&mut reader)?;
+        if header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(usize::MAX);
+            }
+        }
+    }
+    let (status, content_type, body) = if length > MAX_BODY {
+        let message = format!("request body over {MAX_BODY} bytes");
+        ("413 Payload Too Large", "text/plain", message)
+    } else {
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body)?;
+        handle(method, path, body, emitter)
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+/// Passes a request to the callback and turns its result into a response.
+fn handle(
+    method: String,
+    path: String,
+    body: Vec<u8>,
+    emitter: &Emitter,
+) -> (&'static str, &'static str, String) {
+    let mut request = Properties::new();
+    request.set("method", Value::String(method));
+    request.set("path", Value::String(path));
+    request.set(
+        "body",
+        Value::String(String::from_utf8_lossy(&body).into_owned()),
+    );
+    match emitter.request(vec![Value::Compound {
+        properties: request,
+    }]) {
+        Ok(Value::String(s)) => ("200 OK", "text/plain; charset=utf-8", s),
+        Ok(v) => match serde_json::to_string(&v) {
+            Ok(json) => ("200 OK", "application/json", json),
+            Err(e) => ("500 Internal Server Error", "text/plain", e.to_string()),
+        },
+        Err(e) => ("500 Internal Server Error", "text/plain", e.to_string()),
+    }
+}
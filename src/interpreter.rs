@@ -1,11 +1,38 @@
 use crate::ast::*;
+use crate::module::ModuleLoader;
+use crate::stream::Stream;
+use crate::task::{Channel, Task};
 use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, Default, serde::Serialize)]
 pub struct Properties(BTreeMap<String, Value>);
 
+/// Bindings visible to an expression. Closures capture the whole environment,
+/// so it is shared behind an `Arc` and only copied when a binding is added.
+#[derive(Debug, Clone)]
+pub struct Environment {
+    bindings: Arc<BTreeMap<String, Binding>>,
+    /// Bindings every module starts with, i.e. the prelude if it was loaded.
+    root: Arc<BTreeMap<String, Binding>>,
+    modules: Arc<ModuleLoader>,
+    /// Directory of the file being evaluated, for resolving relative imports.
+    dir: Option<Arc<PathBuf>>,
+}
+
+const PRELUDE: &str = include_str!("prelude.haksh");
+
+/// A `let mut` binding holds a cell shared by every environment derived from
+/// the one it was made in, including those captured by closures. Assigning to
+/// it is therefore visible to the defining scope and to every closure that
+/// captured it, even after the closure has been passed elsewhere.
+/// Shadowing it with a new `let` makes a new binding and leaves the cell alone.
 #[derive(Debug, Clone)]
-pub struct Environment(BTreeMap<String, Value>);
+enum Binding {
+    Immutable(Value),
+    Mutable(Arc<std::sync::Mutex<Value>>),
+}
 
 impl Properties {
     pub fn new() -> Properties {
@@ -17,43 +44,355 @@ impl Properties {
     pub fn set(&mut self, name: &str, value: Value) {
         self.0.insert(name.to_string(), value);
     }
+    /// Sets `a.b.c`, creating or copying the compounds along the way.
+    fn set_path(&mut self, path: &[String], value: Value) -> Result<(), Error> {
+        match path {
+            [] => Ok(()),
+            [name] => {
+                self.set(name, value);
+                Ok(())
+            }
+            [name, rest @ ..] => {
+                let mut inner = match self.get(name) {
+                    Some(Value::Compound { properties }) => properties.clone(),
+                    Some(v) => {
+                        return Err(format!("cannot set {}: {name} is {v:?}", path.join(".")).into())
+                    }
+                    None => Properties::new(),
+                };
+                inner.set_path(rest, value)?;
+                self.set(name, Value::Compound { properties: inner });
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The integers `start, start + step, ...` below `end`, or up to it if
+/// `inclusive`. Items are computed on demand, so ranges are never materialized.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct IntRange {
+    start: u64,
+    end: u64,
+    inclusive: bool,
+    step: u64,
+}
+
+impl IntRange {
+    fn last_bound(&self) -> Option<u64> {
+        if self.inclusive {
+            Some(self.end)
+        } else {
+            self.end.checked_sub(1)
+        }
+    }
+    /// The index of the last item, or `None` if the range is empty.
+    fn last_index(&self) -> Option<u64> {
+        match self.last_bound() {
+            Some(last) if last >= self.start => Some((last - self.start) / self.step),
+            _ => None,
+        }
+    }
+    /// The number of items, or `None` if it doesn't fit in a `u64`, as for
+    /// `0..=18446744073709551615`.
+    fn len(&self) -> Option<u64> {
+        self.last_index()
+            .map_or(Some(0), |last| last.checked_add(1))
+    }
+    fn get(&self, index: u64) -> Option<u64> {
+        match self.last_index() {
+            Some(last) if index <= last => Some(self.start + index * self.step),
+            _ => None,
+        }
+    }
+    fn rest(&self) -> IntRange {
+        match self.get(1) {
+            Some(start) => IntRange { start, ..*self },
+            None => IntRange {
+                end: self.start,
+                inclusive: false,
+                ..*self
+            },
+        }
+    }
+    fn iter(&self) -> impl Iterator<Item = u64> {
+        let range = *self;
+        let indices = range.last_index().into_iter().flat_map(|last| 0..=last);
+        indices.map(move |i| range.start + i * range.step)
+    }
+}
+
+impl Default for Environment {
+    fn default() -> Environment {
+        Environment::new()
+    }
 }
 
 impl Environment {
     pub fn new() -> Environment {
-        Environment(BTreeMap::new())
+        Environment {
+            bindings: Arc::new(BTreeMap::new()),
+            root: Arc::new(BTreeMap::new()),
+            modules: Arc::new(ModuleLoader::from_env()),
+            dir: None,
+        }
     }
-    pub fn get(&self, name: &str) -> Option<&Value> {
-        self.0.get(name)
+    /// Adds the bindings exported by the bundled prelude, `src/prelude.haksh`.
+    pub fn with_prelude(&self) -> Result<Environment, Error> {
+        let (_, block) =
+            crate::parser::parse_file(PRELUDE).map_err(|e| Error::new("prelude", e.to_string()))?;
+        let prelude = block.evaluate_module(self)?;
+        let env = prelude
+            .try_get_compound()
+            .unwrap_or_default()
+            .0
+            .into_iter()
+            .fold(self.clone(), |env, (name, value)| env.set(&name, value));
+        Ok(Environment {
+            root: env.bindings.clone(),
+            ..env
+        })
+    }
+    /// The environment for evaluating the file at `path`.
+    pub fn in_file(&self, path: &Path) -> Environment {
+        Environment {
+            dir: path.parent().map(|dir| Arc::new(dir.to_path_buf())),
+            ..self.clone()
+        }
+    }
+    pub fn get(&self, name: &str) -> Option<Value> {
+        match self.bindings.get(name)? {
+            Binding::Immutable(value) => Some(value.clone()),
+            Binding::Mutable(cell) => Some(cell.lock().unwrap().clone()),
+        }
     }
     pub fn set(&self, name: &str, value: Value) -> Environment {
-        let mut new = self.clone();
-        new.0.insert(name.to_string(), value);
-        new
+        self.bind(name, Binding::Immutable(value))
+    }
+    pub fn set_mut(&self, name: &str, value: Value) -> Environment {
+        self.bind(
+            name,
+            Binding::Mutable(Arc::new(std::sync::Mutex::new(value))),
+        )
+    }
+    pub fn assign(&self, name: &str, value: Value) -> Result<(), Error> {
+        match self.bindings.get(name) {
+            Some(Binding::Mutable(cell)) => {
+                *cell.lock().unwrap() = value;
+                Ok(())
+            }
+            Some(Binding::Immutable(_)) => {
+                Err(format!("cannot assign twice to immutable variable {name}").into())
+            }
+            None => Err(format!("no variable named {name} found").into()),
+        }
+    }
+    fn bind(&self, name: &str, binding: Binding) -> Environment {
+        let mut new = self.bindings.as_ref().clone();
+        new.insert(name.to_string(), binding);
+        Environment {
+            bindings: Arc::new(new),
+            ..self.clone()
+        }
+    }
+    /// Binds the names in `pattern` to the matching parts of `value`.
+    fn bind_pattern(
+        &self,
+        pattern: &Pattern,
+        value: Value,
+        mutable: bool,
+    ) -> Result<Environment, Error> {
+        let mismatch = |expected: &str, value: &Value| {
+            Error::new("pattern", format!("expected {expected} but got {value:?}"))
+        };
+        match pattern {
+            Pattern::Name(name) if mutable => Ok(self.set_mut(name, value)),
+            Pattern::Name(name) => Ok(self.set(name, value)),
+            Pattern::Compound(fields) => {
+                let properties = value
+                    .try_get_compound()
+                    .ok_or_else(|| mismatch("compound", &value))?;
+                fields.iter().try_fold(self.clone(), |env, (key, pattern)| {
+                    let field = properties
+                        .get(key)
+                        .cloned()
+                        .ok_or_else(|| mismatch(&format!("property {key}"), &value))?;
+                    env.bind_pattern(pattern, field, mutable)
+                })
+            }
+            Pattern::List { items, rest } => {
+                let Value::List(values) = &value else {
+                    return Err(mismatch("list", &value));
+                };
+                let length_matches = match rest {
+                    Some(_) => values.len() >= items.len(),
+                    None => values.len() == items.len(),
+                };
+                if !length_matches {
+                    let expected = match rest {
+                        Some(_) => format!("at least {} items", items.len()),
+                        None => format!("{} items", items.len()),
+                    };
+                    return Err(mismatch(&expected, &value));
+                }
+                let env = items
+                    .iter()
+                    .zip(values)
+                    .try_fold(self.clone(), |env, (pattern, v)| {
+                        env.bind_pattern(pattern, v.clone(), mutable)
+                    })?;
+                match rest {
+                    Some(name) => {
+                        let rest = Value::List(values[items.len()..].to_vec());
+                        env.bind_pattern(&Pattern::Name(name.clone()), rest, mutable)
+                    }
+                    None => Ok(env),
+                }
+            }
+            Pattern::Annotated { pattern, ty } => {
+                if !value.conforms(ty)? {
+                    return Err(Error::new(
+                        "type",
+                        format!("expected {ty} but got {value:?}"),
+                    ));
+                }
+                self.bind_pattern(pattern, value, mutable)
+            }
+        }
+    }
+    /// Evaluates the module `spec` once and returns its exports as a compound.
+    fn import(&self, spec: &str) -> EvalResult {
+        let path = self
+            .modules
+            .resolve(spec, self.dir.as_deref().map(|d| d.as_path()))?;
+        self.modules.load(&path, |path| {
+            let source = std::fs::read_to_string(path)
+                .map_err(|e| Error::new("import", format!("{}: {e}", path.display())))?;
+            let (_, block) = crate::parser::parse_file(&source)
+                .map_err(|e| Error::new("import", format!("{}: {e}", path.display())))?;
+            crate::typeck::check(&block).map_err(|errors| {
+                let errors: Vec<_> = errors.iter().map(|e| e.to_string()).collect();
+                Error::new("type", format!("{}: {}", path.display(), errors.join("; ")))
+            })?;
+            let env = Environment {
+                bindings: self.root.clone(),
+                ..self.in_file(path)
+            };
+            block.evaluate_module(&env)
+        })
     }
 }
 
 #[derive(Debug, Clone, serde::Serialize)]
 pub enum Value {
-    Compound {
-        properties: Properties,
-    },
     #[serde(skip)]
     Fn {
         env: Environment,
         body: Block,
-        params: Vec<String>,
+        params: Vec<Pattern>,
         name: Option<String>,
     },
+    #[serde(skip)]
+    Stream(crate::stream::Stream),
+    /// Started by `spawn`.
+    #[serde(skip)]
+    Task(Task),
+    /// Made by `chan`.
+    #[serde(skip)]
+    Channel(Channel),
+    /// A function wrapped by `debounce`, `throttle` or `batch`.
+    #[serde(skip)]
+    Wrapped(crate::combinator::Wrapped),
     Unit,
+    /// An absent value: a missing property, env var or match.
+    #[serde(untagged)]
+    None,
     #[serde(untagged)]
     UInt64(u64),
     #[serde(untagged)]
     Bool(bool),
     #[serde(untagged)]
     String(String),
+    /// Serialized as RFC 3339.
+    #[serde(untagged, serialize_with = "serialize_display")]
+    DateTime(crate::time::DateTime),
+    /// Serialized the way literals are written, e.g. `1m30s`.
+    #[serde(untagged, serialize_with = "serialize_display")]
+    Duration(crate::time::Duration),
+    /// `re"..."`; serialized as its source pattern.
+    #[serde(untagged, serialize_with = "serialize_display")]
+    Regex(regex::Regex),
+    /// `path"..."`
+    #[serde(untagged)]
+    Path(std::path::PathBuf),
+    /// `url"..."`; serialized as the url string.
+    #[serde(untagged, serialize_with = "serialize_display")]
+    Url(url::Url),
+    /// `a..b`; serialized as the list of its items.
+    #[serde(untagged, serialize_with = "serialize_range")]
+    Range(IntRange),
+    /// `sh"..."`: a program and its arguments, run with `.run`.
+    #[serde(untagged)]
+    Command(Vec<String>),
+    #[serde(untagged)]
+    Error(Error),
+    #[serde(untagged)]
+    List(Vec<Value>),
+    #[serde(untagged)]
+    Compound {
+        #[serde(flatten)]
+        properties: Properties,
+    },
+}
+
+fn serialize_range<S: serde::Serializer>(range: &IntRange, s: S) -> Result<S::Ok, S::Error> {
+    s.collect_seq(range.iter())
+}
+
+fn serialize_display<T: std::fmt::Display, S: serde::Serializer>(
+    value: &T,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    s.collect_str(value)
+}
+
+/// An error raised during evaluation. Scripts can catch it with
+/// `try { } catch e { }`, which binds it as a `Value::Error`.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Error {
+    pub kind: String,
+    pub message: String,
+}
+
+impl Error {
+    pub fn new(kind: &str, message: impl Into<String>) -> Error {
+        Error {
+            kind: kind.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error::new("runtime", message)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Error {
+        Error::new("runtime", message)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
 }
 
+impl std::error::Error for Error {}
+
 impl Value {
     fn try_get_u64(&self) -> Option<u64> {
         match self {
@@ -73,50 +412,359 @@ impl Value {
             _ => None,
         }
     }
+    /// A path given either as a string or as a `Value::Path`.
+    fn try_get_path(&self) -> Option<std::path::PathBuf> {
+        match self {
+            Value::String(s) => Some(s.into()),
+            Value::Path(path) => Some(path.clone()),
+            _ => None,
+        }
+    }
+    /// A url given either as a string or as a `url"..."` literal.
+    fn try_get_url(&self) -> Option<String> {
+        match self {
+            Value::String(s) => Some(s.clone()),
+            Value::Url(url) => Some(url.to_string()),
+            _ => None,
+        }
+    }
+    /// Converts a `json"..."` literal, whose numbers are known to be unsigned integers.
+    fn from_json(json: &serde_json::Value) -> Value {
+        match json {
+            serde_json::Value::Null => Value::None,
+            serde_json::Value::Bool(b) => Value::Bool(*b),
+            serde_json::Value::Number(n) => Value::UInt64(n.as_u64().unwrap_or_default()),
+            serde_json::Value::String(s) => Value::String(s.clone()),
+            serde_json::Value::Array(items) => {
+                Value::List(items.iter().map(Value::from_json).collect())
+            }
+            serde_json::Value::Object(fields) => {
+                let mut properties = Properties::new();
+                for (k, v) in fields {
+                    properties.set(k, Value::from_json(v));
+                }
+                Value::Compound { properties }
+            }
+        }
+    }
     fn try_get_compound(&self) -> Option<Properties> {
         match self {
             Value::Compound { properties } => Some(properties.clone()),
             _ => None,
         }
     }
-    fn try_evaluate_as_fn(&self, arguments: Vec<Value>) -> EvalResult {
+    /// The name annotations use for the value's type.
+    fn type_name(&self) -> &'static str {
         match self {
-            Value::Fn {
-                env,
-                body,
-                params,
-                name,
-            } => {
-                let env = if let Some(name) = name {
-                    env.set(&name, self.clone())
-                } else {
-                    env.clone()
+            Value::Fn { .. } | Value::Wrapped(_) => "Fn",
+            Value::Stream(_) => "Stream",
+            Value::Task(_) => "Task",
+            Value::Channel(_) => "Channel",
+            Value::Unit => "Unit",
+            Value::None => "None",
+            Value::UInt64(_) => "Int",
+            Value::Bool(_) => "Bool",
+            Value::String(_) => "String",
+            Value::DateTime(_) => "DateTime",
+            Value::Duration(_) => "Duration",
+            Value::Regex(_) => "Regex",
+            Value::Path(_) => "Path",
+            Value::Url(_) => "Url",
+            Value::Range(_) => "Range",
+            Value::Command(_) => "Command",
+            Value::Error(_) => "Error",
+            Value::List(_) => "List",
+            Value::Compound { .. } => "Compound",
+        }
+    }
+    /// Whether the value has the annotated type. Compounds may carry fields the type doesn't list.
+    fn conforms(&self, ty: &TypeExpr) -> Result<bool, Error> {
+        Ok(match (ty, self) {
+            (TypeExpr::Optional(_), Value::None) => true,
+            (TypeExpr::Optional(ty), value) => value.conforms(ty)?,
+            (TypeExpr::List(ty), Value::List(items)) => {
+                for item in items {
+                    if !item.conforms(ty)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            (TypeExpr::Compound(fields), Value::Compound { properties }) => {
+                for (name, ty) in fields {
+                    let field = properties.get(name).cloned().unwrap_or(Value::None);
+                    if !field.conforms(ty)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            (TypeExpr::List(_) | TypeExpr::Compound(_), _) => false,
+            (TypeExpr::Named(name), value) => match name.as_str() {
+                "Any" => true,
+                name if TYPE_NAMES.contains(&name) => value.type_name() == name,
+                name => return Err(Error::new("type", format!("unknown type {name}"))),
+            },
+        })
+    }
+    fn property(&self, name: &str) -> Option<Value> {
+        match self {
+            Value::Compound { properties } => {
+                Some(properties.get(name).cloned().unwrap_or(Value::None))
+            }
+            Value::Error(e) => match name {
+                "kind" => Some(Value::String(e.kind.clone())),
+                "message" => Some(Value::String(e.message.clone())),
+                _ => None,
+            },
+            Value::Url(url) => {
+                let optional = |s: Option<&str>| {
+                    s.map(|s| Value::String(s.to_string()))
+                        .unwrap_or(Value::None)
                 };
-                let env = params
-                    .iter()
-                    .enumerate()
-                    .try_fold(env, |env, (index, name)| {
-                        let arg = arguments.get(index).ok_or_else(|| {
-                            format!(
-                                "Expected {} arguments but got {}",
-                                params.len(),
-                                arguments.len()
-                            )
-                        })?;
-
-                        let env = env.set(name, arg.clone());
-
-                        Ok::<_, String>(env)
-                    })?;
+                match name {
+                    "scheme" => Some(Value::String(url.scheme().to_string())),
+                    "host" => Some(optional(url.host_str())),
+                    "port" => Some(
+                        url.port_or_known_default()
+                            .map(|p| Value::UInt64(p.into()))
+                            .unwrap_or(Value::None),
+                    ),
+                    "path" => Some(Value::String(url.path().to_string())),
+                    "query" => Some(optional(url.query())),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+    /// Whether two values are structurally equal. Functions and streams are never equal.
+    pub(crate) fn same(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Unit, Value::Unit) | (Value::None, Value::None) => true,
+            (Value::UInt64(a), Value::UInt64(b)) => a == b,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::String(a), Value::String(b)) => a == b,
+            (Value::DateTime(a), Value::DateTime(b)) => a == b,
+            (Value::Duration(a), Value::Duration(b)) => a == b,
+            (Value::Regex(a), Value::Regex(b)) => a.as_str() == b.as_str(),
+            (Value::Path(a), Value::Path(b)) => a == b,
+            (Value::Url(a), Value::Url(b)) => a == b,
+            (Value::Range(a), Value::Range(b)) => a == b,
+            (Value::Command(a), Value::Command(b)) => a == b,
+            (Value::Error(a), Value::Error(b)) => a == b,
+            (Value::List(a), Value::List(b)) => {
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.same(b))
+            }
+            (Value::Compound { properties: a }, Value::Compound { properties: b }) => {
+                a.0.len() == b.0.len()
+                    && a.0
+                        .iter()
+                        .zip(&b.0)
+                        .all(|((ka, a), (kb, b))| ka == kb && a.same(b))
+            }
+            _ => false,
+        }
+    }
+    pub(crate) fn try_evaluate_as_fn(&self, arguments: Vec<Value>) -> EvalResult {
+        let _depth = CallDepth::enter()?;
+
+        // Tail calls come back as `Tail::Call` and are run by this loop,
+        // so only non-tail calls grow the native stack.
+        let mut function = self.clone();
+        let mut arguments = arguments;
+        loop {
+            check_cancelled()?;
+            match function {
+                Value::Fn {
+                    env,
+                    body,
+                    params,
+                    name,
+                } => {
+                    let env = if let Some(name) = &name {
+                        env.set(
+                            name,
+                            Value::Fn {
+                                env: env.clone(),
+                                body: body.clone(),
+                                params: params.clone(),
+                                name: Some(name.clone()),
+                            },
+                        )
+                    } else {
+                        env
+                    };
+                    let env =
+                        params
+                            .iter()
+                            .enumerate()
+                            .try_fold(env, |env, (index, pattern)| {
+                                let arg = arguments.get(index).ok_or_else(|| {
+                                    format!(
+                                        "Expected {} arguments but got {}",
+                                        params.len(),
+                                        arguments.len()
+                                    )
+                                })?;
+
+                                env.bind_pattern(pattern, arg.clone(), false)
+                            })?;
+
+                    match body.evaluate_tail(&env)? {
+                        Tail::Value(v) => return Ok(v),
+                        Tail::Call(next, args) => {
+                            function = next;
+                            arguments = args;
+                        }
+                    }
+                }
+                Value::Wrapped(wrapped) => return wrapped.call(arguments),
+                _ => return Err("Not fn".into()),
+            }
+        }
+    }
+}
+
+/// Maximum number of nested non-tail function calls before evaluation fails
+/// with a recursion limit error instead of overflowing the native stack.
+pub const MAX_CALL_DEPTH: usize = 2000;
+
+/// Native stack size the interpreter needs to reach `MAX_CALL_DEPTH`.
+/// Threads that evaluate haksh code should be spawned with at least this much.
+pub const STACK_SIZE: usize = 256 * 1024 * 1024;
+
+thread_local! {
+    static CALL_DEPTH: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Set by `cancel` and never cleared, so that every thread stops: the main
+/// script and every task.
+static CANCELLED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Set by `interrupt`; only cancels code run by `run_interruptible`.
+static INTERRUPTED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+thread_local! {
+    /// Whether this thread is in `run_interruptible`.
+    static INTERRUPTIBLE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+    /// How many `defer` or `finally` blocks this thread is running.
+    static CLEANING_UP: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+/// Asks the running script and all its tasks to stop, e.g. on Ctrl-C. Their
+/// next function call or wait fails with a `cancelled` error that `catch`
+/// doesn't stop, so only pending `defer` and `finally` blocks run on the way
+/// out. Returns whether a cancel was already pending.
+pub fn cancel() -> bool {
+    CANCELLED.swap(true, std::sync::atomic::Ordering::SeqCst)
+}
+
+/// Like `cancel`, but only for code run by `run_interruptible`, such as the
+/// line the REPL is evaluating. Returns whether an interrupt was already pending.
+pub fn interrupt() -> bool {
+    INTERRUPTED.swap(true, std::sync::atomic::Ordering::SeqCst)
+}
+
+/// Runs `f` on this thread so that `interrupt` cancels it. Tasks it spawns
+/// keep running.
+pub fn run_interruptible<T>(f: impl FnOnce() -> T) -> T {
+    INTERRUPTED.store(false, std::sync::atomic::Ordering::SeqCst);
+    INTERRUPTIBLE.with(|i| i.set(true));
+    let result = f();
+    INTERRUPTIBLE.with(|i| i.set(false));
+    INTERRUPTED.store(false, std::sync::atomic::Ordering::SeqCst);
+    result
+}
+
+/// Whether code on this thread should stop. Cleanup code isn't stopped, so
+/// that it can run to the end.
+fn cancellation_pending() -> bool {
+    let ordering = std::sync::atomic::Ordering::SeqCst;
+    CLEANING_UP.with(|c| c.get() == 0)
+        && (CANCELLED.load(ordering)
+            || crate::task::is_cancelled()
+            || INTERRUPTIBLE.with(|i| i.get()) && INTERRUPTED.load(ordering))
+}
+
+pub(crate) fn check_cancelled() -> Result<(), Error> {
+    if cancellation_pending() {
+        Err(Error::new("cancelled", "interrupted"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Runs `f` as cleanup code, which cancellation doesn't stop.
+pub(crate) fn cleaning_up<T>(f: impl FnOnce() -> T) -> T {
+    let _cleaning_up = CleaningUp::enter();
+    f()
+}
+
+/// Marks this thread as running cleanup code while it is alive.
+struct CleaningUp;
+
+impl CleaningUp {
+    fn enter() -> CleaningUp {
+        CLEANING_UP.with(|c| c.set(c.get() + 1));
+        CleaningUp
+    }
+}
+
+impl Drop for CleaningUp {
+    fn drop(&mut self) {
+        CLEANING_UP.with(|c| c.set(c.get() - 1));
+    }
+}
+
+struct CallDepth;
 
-                body.evaluate(&env)
+impl CallDepth {
+    fn enter() -> Result<CallDepth, Error> {
+        CALL_DEPTH.with(|depth| {
+            if depth.get() >= MAX_CALL_DEPTH {
+                Err(Error::new(
+                    "recursion",
+                    format!("recursion limit of {MAX_CALL_DEPTH} calls exceeded"),
+                ))
+            } else {
+                depth.set(depth.get() + 1);
+                Ok(CallDepth)
             }
-            _ => Err("Not fn".to_string()),
+        })
+    }
+}
+
+impl Drop for CallDepth {
+    fn drop(&mut self) {
+        CALL_DEPTH.with(|depth| depth.set(depth.get() - 1));
+    }
+}
+
+/// The result of evaluating an expression in tail position: either a value,
+/// or a call that the enclosing function should make in place of itself.
+enum Tail {
+    Value(Value),
+    Call(Value, Vec<Value>),
+}
+
+impl Tail {
+    fn resolve(self) -> EvalResult {
+        match self {
+            Tail::Value(v) => Ok(v),
+            Tail::Call(f, args) => f.try_evaluate_as_fn(args),
         }
     }
 }
 
-type EvalResult = Result<Value, String>;
+/// Type names an annotation may use besides `Any`, lists and compounds.
+const TYPE_NAMES: &[&str] = &[
+    "Fn", "Stream", "Task", "Channel", "Unit", "None", "Int", "Bool", "String", "DateTime",
+    "Duration", "Regex", "Path", "Url", "Range", "Command", "Error",
+];
+
+pub type EvalResult = Result<Value, Error>;
+type TailResult = Result<Tail, Error>;
 
 impl BoolLiteral {
     fn evaluate(&self) -> Value {
@@ -126,58 +774,170 @@ impl BoolLiteral {
 
 impl Block {
     pub fn evaluate(&self, env: &Environment) -> EvalResult {
-        fn a(
-            mut expr: std::collections::VecDeque<BlockElement>,
-            env: Environment,
-            value: Value,
-        ) -> EvalResult {
-            Ok(if let Some(e) = expr.pop_front() {
-                match e {
-                    BlockElement::Expr(e) => {
-                        let v = e.evaluate(&env)?;
-                        a(expr, env, v)?
-                    }
-                    BlockElement::Var { name, def } => {
-                        let env = env.set(&name, def.evaluate(&env)?);
-                        a(expr, env, Value::Unit)?
+        self.evaluate_tail(env)?.resolve()
+    }
+
+    /// Whether the block is just a `fn` literal, possibly in parentheses.
+    fn is_function_literal(&self) -> bool {
+        match &self.0[..] {
+            [BlockElement::AnonymousFunction(_)] => true,
+            [BlockElement::Expr(Expr::Primary(PrimaryExpr::Block(b)))] => b.is_function_literal(),
+            _ => false,
+        }
+    }
+
+    /// Evaluates a whole script, then runs its event loop until every source
+    /// it registered, such as an `fs.watch` callback, is finished.
+    pub fn run(&self, env: &Environment) -> EvalResult {
+        let value = self.evaluate(env)?;
+        crate::reactor::run()?;
+        Ok(value)
+    }
+
+    fn evaluate_tail(&self, env: &Environment) -> TailResult {
+        let mut env = env.clone();
+        let mut value = Value::Unit;
+        let mut deferred = Vec::new();
+        let registered = crate::reactor::registered();
+        for (index, e) in self.0.iter().enumerate() {
+            let is_last = index + 1 == self.0.len();
+            let result = match e {
+                BlockElement::Defer(b) => {
+                    deferred.push((env.clone(), b));
+                    value = Value::Unit;
+                    continue;
+                }
+                BlockElement::Expr(e) if is_last => e.evaluate_tail(&env),
+                BlockElement::Using { name, def } => {
+                    let mut def = def.clone();
+                    def.args.push(PrimaryExpr::Block(Block(vec![
+                        BlockElement::AnonymousFunction(AnonymousFunction {
+                            params: vec![Pattern::Name(name.clone())],
+                            body: Block(self.0[index + 1..].to_vec()),
+                        }),
+                    ])));
+
+                    def.evaluate_tail(&env)
+                }
+                e => match e.evaluate_element(&env) {
+                    Ok((new_env, v)) => {
+                        (env, value) = (new_env, v);
+                        continue;
                     }
-                    BlockElement::AnonymousFunction(AnonymousFunction { params, body }) => a(
-                        expr,
-                        env.clone(),
-                        Value::Fn {
-                            env: env.clone(),
-                            name: None,
-                            params,
-                            body,
-                        },
-                    )?,
-                    BlockElement::Using { name, mut def } => {
-                        def.args.push(PrimaryExpr::Block(Block(vec![
-                            BlockElement::AnonymousFunction(AnonymousFunction {
-                                params: vec![name],
-                                body: Block(expr.into()),
-                            }),
-                        ])));
-
-                        def.evaluate(&env)?
+                    Err(e) => Err(e),
+                },
+            };
+            return run_deferred(result, deferred, registered);
+        }
+        run_deferred(Ok(Tail::Value(value)), deferred, registered)
+    }
+
+    /// Evaluates a module's top level and collects its exported bindings.
+    /// Exports are copied out once the module has been evaluated.
+    fn evaluate_module(&self, env: &Environment) -> EvalResult {
+        let mut env = env.clone();
+        let mut exports = Properties::new();
+        for e in &self.0 {
+            match e {
+                BlockElement::Using { .. } => {
+                    return Err(Error::new(
+                        "import",
+                        "'using' is not allowed at the top level of a module",
+                    ))
+                }
+                BlockElement::Defer(_) => {
+                    return Err(Error::new(
+                        "import",
+                        "'defer' is not allowed at the top level of a module",
+                    ))
+                }
+                BlockElement::Export(e) => {
+                    (env, _) = e.evaluate_element(&env)?;
+                    let names = match &**e {
+                        BlockElement::Var { pattern, .. } => pattern.names(),
+                        BlockElement::Fn { name, .. } => vec![name.as_str()],
+                        _ => vec![],
+                    };
+                    for name in names {
+                        exports.set(name, env.get(name).unwrap_or(Value::Unit));
                     }
                 }
-            } else {
-                value
-            })
+                e => (env, _) = e.evaluate_element(&env)?,
+            }
         }
-        a(self.0.clone().into(), env.clone(), Value::Unit)
+        Ok(Value::Compound {
+            properties: exports,
+        })
     }
 }
 
+/// Runs a block's `defer` blocks, last first, once it is done. A tail call
+/// has to be made before that, so a block with defers gives up tail calls.
+/// An error from the block takes precedence over one raised while cleaning up.
+/// Cancellation doesn't stop them, so a second Ctrl-C is needed to abandon them.
+///
+/// If the block registered event sources since `registered`, their callbacks
+/// only run once it is done, so the defers wait for the event loop to stop.
+fn run_deferred(
+    result: TailResult,
+    deferred: Vec<(Environment, &Block)>,
+    registered: u64,
+) -> TailResult {
+    if deferred.is_empty() {
+        return result;
+    }
+    let mut result = result.and_then(Tail::resolve);
+    if crate::reactor::registered() != registered {
+        let deferred: Vec<_> = deferred
+            .into_iter()
+            .map(|(env, block)| (env, block.clone()))
+            .collect();
+        crate::reactor::defer(move || {
+            let mut result = Ok(());
+            for (env, block) in deferred.into_iter().rev() {
+                result = result.and(block.evaluate(&env).map(|_| ()));
+            }
+            result
+        });
+        return result.map(Tail::Value);
+    }
+    let _cleaning_up = CleaningUp::enter();
+    for (env, block) in deferred.into_iter().rev() {
+        if let Err(e) = block.evaluate(&env) {
+            if result.is_ok() {
+                result = Err(e);
+            }
+        }
+    }
+    result.map(Tail::Value)
+}
+
 impl BlockElement {
-    pub fn evaluate_for_repl(&self, env: &Environment) -> Result<(Environment, Value), String> {
+    pub fn evaluate_for_repl(&self, env: &Environment) -> Result<(Environment, Value), Error> {
+        match self {
+            // The REPL collects the continuation itself and evaluates the whole block.
+            BlockElement::Using { .. } => Err("'using' needs the lines that follow it".into()),
+            BlockElement::Defer(_) => Err("'defer' only works inside a block".into()),
+            e => e.evaluate_element(env),
+        }
+    }
+
+    /// Evaluates every element except `using`, which needs the rest of its block.
+    fn evaluate_element(&self, env: &Environment) -> Result<(Environment, Value), Error> {
         match self {
             BlockElement::Expr(e) => Ok((env.clone(), e.evaluate(env)?)),
-            BlockElement::Var { name, def } => {
-                let env = env.set(name, def.evaluate(env)?);
+            BlockElement::Var {
+                pattern,
+                def,
+                mutable,
+            } => {
+                let env = env.bind_pattern(pattern, def.evaluate(env)?, *mutable)?;
                 Ok((env, Value::Unit))
             }
+            BlockElement::Assign { name, def } => {
+                env.assign(name, def.evaluate(env)?)?;
+                Ok((env.clone(), Value::Unit))
+            }
             BlockElement::AnonymousFunction(AnonymousFunction { params, body }) => Ok((
                 env.clone(),
                 Value::Fn {
@@ -187,38 +947,85 @@ impl BlockElement {
                     body: body.clone(),
                 },
             )),
-            BlockElement::Using { .. } => {
-                Err("'using' statement does not work in REPL".to_string())
+            BlockElement::Fn { name, function } => {
+                let env = env.set(
+                    name,
+                    Value::Fn {
+                        env: env.clone(),
+                        name: Some(name.clone()),
+                        params: function.params.clone(),
+                        body: function.body.clone(),
+                    },
+                );
+                Ok((env, Value::Unit))
+            }
+            BlockElement::Import { path, name } => {
+                let module = env.import(path)?;
+                Ok((env.set(name, module), Value::Unit))
             }
+            BlockElement::Export(e) => e.evaluate_element(env),
+            BlockElement::Using { .. } => unreachable!("using is evaluated by its block"),
+            BlockElement::Defer(_) => unreachable!("defer is evaluated by its block"),
         }
     }
 }
 
 impl PrimaryExpr {
     fn evaluate(&self, env: &Environment) -> EvalResult {
+        self.evaluate_tail(env)?.resolve()
+    }
+
+    fn evaluate_tail(&self, env: &Environment) -> TailResult {
+        if let PrimaryExpr::Block(b) = self {
+            return b.evaluate_tail(env);
+        }
+        self.evaluate_value(env).map(Tail::Value)
+    }
+
+    fn evaluate_value(&self, env: &Environment) -> EvalResult {
         match self {
             PrimaryExpr::Bool(b) => Ok(b.evaluate()),
-            PrimaryExpr::Block(b) => b.evaluate(&env),
+            PrimaryExpr::Block(b) => b.evaluate(env),
             PrimaryExpr::DecimalInt(n) => Ok(Value::UInt64(*n)),
+            PrimaryExpr::Duration(d) => Ok(Value::Duration(*d)),
             PrimaryExpr::StringLiteral(s) => Ok(Value::String(s.clone())),
+            PrimaryExpr::None => Ok(Value::None),
             PrimaryExpr::TaggedString(ts) => match ts {
-                TaggedString::Regex(r) => {
-                    todo!("implement regex");
-                }
+                TaggedString::Regex(re) => Ok(Value::Regex(re.clone())),
+                TaggedString::Json(json) => Ok(Value::from_json(json)),
+                TaggedString::Path(path) => Ok(Value::Path(path.clone())),
+                TaggedString::Url(url) => Ok(Value::Url(url.clone())),
+                TaggedString::Sh(words) => Ok(Value::Command(words.clone())),
+                TaggedString::Value(value) => Ok(value.clone()),
             },
             PrimaryExpr::Identifier(name) => env
                 .get(name)
-                .cloned()
-                .ok_or(format!("no variable named {name} found")),
-            PrimaryExpr::Compound(c) => {
-                let a = c
+                .ok_or(format!("no variable named {name} found").into()),
+            PrimaryExpr::List(items) => Ok(Value::List(
+                items
                     .iter()
-                    .map(|(k, v)| v.evaluate(env).map(|v| (k.to_string(), v)))
-                    .collect::<Result<std::collections::BTreeMap<_, _>, String>>()?;
+                    .map(|e| e.evaluate(env))
+                    .collect::<Result<Vec<_>, Error>>()?,
+            )),
+            PrimaryExpr::Compound(c) => {
+                // Entries apply left to right, so later ones override earlier ones.
+                let mut properties = Properties::new();
+                for entry in c {
+                    match entry {
+                        CompoundEntry::Spread(base) => {
+                            let base = base.evaluate(env)?;
+                            let base = base
+                                .try_get_compound()
+                                .ok_or(format!("cannot spread {base:?}, it is not compound"))?;
+                            properties.0.extend(base.0);
+                        }
+                        CompoundEntry::Field { path, def } => {
+                            properties.set_path(path, def.evaluate(env)?)?;
+                        }
+                    }
+                }
 
-                Ok(Value::Compound {
-                    properties: Properties(a),
-                })
+                Ok(Value::Compound { properties })
             }
         }
     }
@@ -239,13 +1046,35 @@ impl<T: BinaryOperator> BinOp<T> {
 
 impl BinaryOperator for AddSubOp {
     fn op(&self, left: Value, right: Value) -> EvalResult {
+        let time = match (self, &left, &right) {
+            (Self::Add, Value::DateTime(t), Value::Duration(d))
+            | (Self::Add, Value::Duration(d), Value::DateTime(t)) => {
+                Some(t.checked_add(*d).map(Value::DateTime))
+            }
+            (Self::Sub, Value::DateTime(t), Value::Duration(d)) => {
+                Some(t.checked_sub(*d).map(Value::DateTime))
+            }
+            (Self::Sub, Value::DateTime(a), Value::DateTime(b)) => {
+                Some(a.since(b).map(Value::Duration))
+            }
+            (Self::Add, Value::Duration(a), Value::Duration(b)) => {
+                Some(a.checked_add(*b).map(Value::Duration))
+            }
+            (Self::Sub, Value::Duration(a), Value::Duration(b)) => {
+                Some(a.checked_sub(*b).map(Value::Duration))
+            }
+            _ => None,
+        };
+        if let Some(result) = time {
+            return result.ok_or(Error::new("time", "time out of range"));
+        }
         let left = left.try_get_u64().ok_or(format!("not int: {:?}", left))?;
         let right = right.try_get_u64().ok_or(format!("not int: {:?}", right))?;
         let result = match self {
-            Self::Add => left + right,
-            Self::Sub => left - right,
+            Self::Add => left.checked_add(right),
+            Self::Sub => left.checked_sub(right),
         };
-        Ok(Value::UInt64(result))
+        Ok(Value::UInt64(result.ok_or("integer overflow".to_string())?))
     }
     fn into_expr() -> impl Fn(BinOp<AddSubOp>) -> Expr {
         Expr::AddSub
@@ -254,10 +1083,34 @@ impl BinaryOperator for AddSubOp {
 
 impl BinaryOperator for MulDivOp {
     fn op(&self, left: Value, right: Value) -> EvalResult {
+        if let (Self::Div, Value::Path(base)) = (self, &left) {
+            let child = right
+                .try_get_path()
+                .ok_or(format!("cannot join {right:?} to a path"))?;
+            return Ok(Value::Path(base.join(child)));
+        }
+        match (self, &left, &right) {
+            (Self::Mul, Value::Duration(d), Value::UInt64(n))
+            | (Self::Mul, Value::UInt64(n), Value::Duration(d)) => {
+                return d
+                    .checked_mul(*n)
+                    .map(Value::Duration)
+                    .ok_or(Error::new("time", "time out of range"));
+            }
+            (Self::Div, Value::Duration(d), Value::UInt64(n)) => {
+                return d
+                    .checked_div(*n)
+                    .map(Value::Duration)
+                    .ok_or("division by zero".into());
+            }
+            _ => {}
+        }
         let left = left.try_get_u64().ok_or(format!("not int: {:?}", left))?;
         let right = right.try_get_u64().ok_or(format!("not int: {:?}", right))?;
         let result = match self {
-            Self::Mul => left * right,
+            Self::Mul => left
+                .checked_mul(right)
+                .ok_or("integer overflow".to_string())?,
             Self::Div => left
                 .checked_div(right)
                 .ok_or("division by zero".to_string())?,
@@ -270,48 +1123,79 @@ impl BinaryOperator for MulDivOp {
     }
 }
 
-impl FunctionApplication {
-    fn evaluate(&self, env: &Environment) -> EvalResult {
-        match self.fident.clone() {
-            i if i
-                == Identifier {
-                    path: "fs".to_string(),
-                    child: Some(Box::new(Identifier {
-                        path: "cwd".to_string(),
-                        child: None,
-                    })),
-                } =>
-            {
-                let current_dir = std::env::current_dir().map_err(|e| "IOError")?;
-
-                Ok(current_dir
-                    .to_str()
-                    .ok_or(format!("to_str error"))
-                    .map(|s| Value::String(s.to_string()))?)
-            }
-            i if i
-                == Identifier {
-                    path: "println".to_string(),
-                    child: None,
-                } =>
-            {
-                // 遅そう
-                let s = self
-                    .args
+impl BinaryOperator for CompareOp {
+    fn op(&self, left: Value, right: Value) -> EvalResult {
+        // Values that can't be ordered, such as lists, can still be equal.
+        let same_kind = std::mem::discriminant(&left) == std::mem::discriminant(&right);
+        match self {
+            Self::Eq if same_kind => return Ok(Value::Bool(left.same(&right))),
+            Self::Ne if same_kind => return Ok(Value::Bool(!left.same(&right))),
+            _ => {}
+        }
+        let ordering = match (&left, &right) {
+            (Value::UInt64(l), Value::UInt64(r)) => l.cmp(r),
+            (Value::String(l), Value::String(r)) => l.cmp(r),
+            (Value::Path(l), Value::Path(r)) => l.cmp(r),
+            (Value::DateTime(l), Value::DateTime(r)) => l.cmp(r),
+            (Value::Duration(l), Value::Duration(r)) => l.cmp(r),
+            (Value::Bool(l), Value::Bool(r)) => l.cmp(r),
+            (Value::Unit, Value::Unit) => std::cmp::Ordering::Equal,
+            (Value::None, _) | (_, Value::None) => {
+                let both = matches!((&left, &right), (Value::None, Value::None));
+                return match self {
+                    Self::Eq => Ok(Value::Bool(both)),
+                    Self::Ne => Ok(Value::Bool(!both)),
+                    _ => Err(format!("cannot order {left:?} and {right:?}").into()),
+                };
+            }
+            _ => return Err(format!("cannot compare {left:?} with {right:?}").into()),
+        };
+        let result = match self {
+            Self::Eq => ordering.is_eq(),
+            Self::Ne => ordering.is_ne(),
+            Self::Lt => ordering.is_lt(),
+            Self::Le => ordering.is_le(),
+            Self::Gt => ordering.is_gt(),
+            Self::Ge => ordering.is_ge(),
+        };
+
+        Ok(Value::Bool(result))
+    }
+    fn into_expr() -> impl Fn(BinOp<Self>) -> Expr {
+        Expr::Compare
+    }
+}
+
+impl FunctionApplication {
+    fn evaluate_tail(&self, env: &Environment) -> TailResult {
+        // Builtins live in their own frame so that it isn't kept on the
+        // native stack while a user-defined function runs.
+        match self.evaluate_builtin(env)? {
+            Some(value) => Ok(Tail::Value(value)),
+            None => self.apply(env),
+        }
+    }
+
+    fn evaluate_builtin(&self, env: &Environment) -> Result<Option<Value>, Error> {
+        let value = match self.fident.clone() {
+            i if i.is(&["fs", "cwd"]) => {
+                let current_dir =
+                    std::env::current_dir().map_err(|e| Error::new("io", e.to_string()))?;
+                Ok(Value::Path(current_dir))
+            }
+            i if i.is(&["println"]) => {
+                // 遅そう
+                let s = self
+                    .args
                     .iter()
                     .map(|a| Ok(format!("{:?}", a.evaluate(env)?)))
-                    .collect::<Result<Vec<_>, String>>()?
+                    .collect::<Result<Vec<_>, Error>>()?
                     .join(" ");
                 println!("{s}");
 
                 Ok(Value::Unit)
             }
-            i if i
-                == Identifier {
-                    path: "twice".to_string(),
-                    child: None,
-                } =>
-            {
+            i if i.is(&["twice"]) => {
                 let continuation = self.args.last().ok_or("no arguments".to_string())?;
                 let _ = continuation
                     .evaluate(env)?
@@ -322,46 +1206,24 @@ impl FunctionApplication {
 
                 Ok(Value::Unit)
             }
-
-            i if i
-                == Identifier {
-                    path: "http".to_string(),
-                    child: Some(Box::new(Identifier {
-                        path: "get".to_string(),
-                        child: None,
-                    })),
-                } =>
-            {
+            i if i.is(&["http", "get"]) => {
                 let mut args = self.args.clone();
                 let url = args.pop().ok_or("no arguments")?.evaluate(env)?;
-                let url = url
-                    .try_get_string()
-                    .ok_or(format!("{url:?} is not string"))?;
+                let url = url.try_get_url().ok_or(format!("{url:?} is not a url"))?;
 
-                let res = reqwest::blocking::get(url).map_err(|e| e.to_string())?;
+                let res = reqwest::blocking::get(url)
+                    .and_then(|res| res.error_for_status())
+                    .map_err(|e| Error::new("http", e.to_string()))?;
 
-                let body = res.text().map_err(|e| e.to_string())?;
+                let body = res.text().map_err(|e| Error::new("http", e.to_string()))?;
 
                 Ok(Value::String(body))
             }
-            i if i
-                == Identifier {
-                    path: "http".to_string(),
-                    child: Some(Box::new(Identifier {
-                        path: "post".to_string(),
-                        child: Some(Box::new(Identifier {
-                            path: "json".to_string(),
-                            child: None,
-                        })),
-                    })),
-                } =>
-            {
+            i if i.is(&["http", "post", "json"]) => {
                 let mut args = self.args.clone();
                 let body = args.pop().ok_or("no arguments")?.evaluate(env)?;
                 let url = args.pop().ok_or("no arguments")?.evaluate(env)?;
-                let url = url
-                    .try_get_string()
-                    .ok_or(format!("{url:?} is not string"))?;
+                let url = url.try_get_url().ok_or(format!("{url:?} is not a url"))?;
                 let body = body
                     .try_get_compound()
                     .ok_or(format!("{url:?} is not compound"))?;
@@ -373,137 +1235,1654 @@ impl FunctionApplication {
                     .header("Content-Type", "application/json")
                     .body(body)
                     .send()
-                    .map_err(|e| e.to_string())?;
+                    .and_then(|res| res.error_for_status())
+                    .map_err(|e| Error::new("http", e.to_string()))?;
 
-                let body = res.text().map_err(|e| e.to_string())?;
+                let body = res.text().map_err(|e| Error::new("http", e.to_string()))?;
 
                 Ok(Value::String(body))
             }
 
-            i if i
-                == Identifier {
-                    path: "fs".to_string(),
-                    child: Some(Box::new(Identifier {
-                        path: "watch".to_string(),
-                        child: None,
-                    })),
-                } =>
-            {
-                use notify::EventKind;
-                use notify::Watcher;
-                use notify_debouncer_full::DebouncedEvent;
-
-                use std::fs::File;
-                use std::io::{self, BufRead, Read, Seek, SeekFrom};
+            i if i.is(&["http", "serve"]) => {
+                let port = self.argument(0, env)?;
+                let port = port
+                    .try_get_u64()
+                    .and_then(|p| u16::try_from(p).ok())
+                    .ok_or(format!("{port:?} is not a port"))?;
+                // Only this machine can connect unless `--host` says otherwise.
+                let host = match self.options.get("host") {
+                    Some(host) => {
+                        let host = host.evaluate(env)?;
+                        host.try_get_string()
+                            .ok_or(format!("--host {host:?} is not string"))?
+                    }
+                    None => "127.0.0.1".to_string(),
+                };
+                let handler = self.argument(1, env)?;
+                crate::reactor::register(handler, |emitter| {
+                    crate::http::serve(&host, port, emitter)
+                })?;
+                Ok(Value::Unit)
+            }
 
-                let (tx, rx) = std::sync::mpsc::channel();
+            i if i.is(&["fs", "watch"]) => {
+                let recursive = match self.options.get("recursive") {
+                    Some(recursive) => {
+                        let recursive = recursive.evaluate(env)?;
+                        recursive
+                            .try_get_bool()
+                            .ok_or(format!("--recursive {recursive:?} is not bool"))?
+                    }
+                    None => false,
+                };
+                // A path, a glob, or a list of them.
+                let target = self.argument(0, env)?;
+                let paths = match &target {
+                    Value::List(items) => items.clone(),
+                    _ => vec![target.clone()],
+                };
+                let targets = paths
+                    .iter()
+                    .map(|p| {
+                        let path = p.try_get_path().ok_or(format!("{p:?} is not a path"))?;
+                        crate::watch::Target::new(&path, recursive)
+                    })
+                    .collect::<Result<Vec<_>, Error>>()?;
+                // With `--state`, offsets are checkpointed once the callback has handled the lines.
+                let state = match self.options.get("state") {
+                    Some(state) => {
+                        let state = state.evaluate(env)?;
+                        Some(
+                            state
+                                .try_get_path()
+                                .ok_or(format!("--state {state:?} is not a path"))?,
+                        )
+                    }
+                    None => None,
+                };
+                // Without a callback the lines are returned for the script to compose.
+                if self.args.len() == 1 {
+                    if state.is_some() {
+                        return Err("fs.watch --state needs a callback".into());
+                    }
+                    return crate::stream::watch_lines(targets).map(|s| Some(Value::Stream(s)));
+                }
+                // The callback gets the line, the file it was read from and the event kind,
+                // so a `batch` callback collects `[line, path, kind]` lists.
+                let callback = self.callback_argument(self.args.len() - 1, env)?;
+                let checkpointed = state.is_some();
+                crate::reactor::register(callback, |emitter| {
+                    crate::watch::watch(targets, state, move |line| {
+                        let line = line.map(|line| {
+                            vec![
+                                Value::String(line.text),
+                                Value::Path(line.path),
+                                Value::String(line.kind.to_string()),
+                            ]
+                        });
+                        match line {
+                            // Waiting for the callback keeps checkpoints behind handled lines.
+                            Ok(arguments) if checkpointed => match emitter.request(arguments) {
+                                Ok(_) => true,
+                                Err(e) => {
+                                    emitter.fail(e);
+                                    false
+                                }
+                            },
+                            Ok(arguments) => emitter.call(arguments),
+                            Err(e) => {
+                                emitter.fail(e);
+                                false
+                            }
+                        }
+                    })
+                })?;
+                Ok(Value::Unit)
+            }
 
-                let f = self
-                    .args
-                    .get(0)
-                    .ok_or("no arguments".to_string())?
-                    .evaluate(env)?
+            // These names are common enough that a script's own bindings win.
+            i if i.is(&["spawn"]) && env.get("spawn").is_none() => {
+                let f = self.callback_argument(0, env)?;
+                Ok(Value::Task(Task::spawn(move || {
+                    f.try_evaluate_as_fn(vec![])
+                })?))
+            }
+            i if i.is(&["join"]) && env.get("join").is_none() => match self.argument(0, env)? {
+                Value::Task(task) => task.join(),
+                // Joining a list waits for every task and fails with the first error.
+                Value::List(tasks) => tasks
+                    .iter()
+                    .map(|task| match task {
+                        Value::Task(task) => task.join(),
+                        v => Err(format!("{v:?} is not a task").into()),
+                    })
+                    .collect::<Result<_, _>>()
+                    .map(Value::List),
+                v => Err(format!("{v:?} is not a task").into()),
+            },
+            i if i.is(&["chan"]) && env.get("chan").is_none() => Ok(Value::Channel(Channel::new())),
+            i if i.is(&["send"]) && env.get("send").is_none() => {
+                let channel = self.channel_argument(0, env)?;
+                channel.send(self.argument(1, env)?)?;
+                Ok(Value::Unit)
+            }
+            i if i.is(&["recv"]) && env.get("recv").is_none() => {
+                let channel = self.channel_argument(0, env)?;
+                // With `--timeout`, none is returned if nothing arrives in time.
+                let timeout = match self.options.get("timeout") {
+                    Some(timeout) => match timeout.evaluate(env)? {
+                        Value::Duration(d) => Some(d.to_std().ok_or("negative timeout")?),
+                        v => return Err(format!("{v:?} is not a duration").into()),
+                    },
+                    None => None,
+                };
+                Ok(channel.recv(timeout)?.unwrap_or(Value::None))
+            }
+            i if i.is(&["sleep"]) && env.get("sleep").is_none() => {
+                crate::timer::sleep(self.duration_argument(0, env)?)?;
+                Ok(Value::Unit)
+            }
+            i if i.is(&["after"]) && env.get("after").is_none() => {
+                let delay = self.duration_argument(0, env)?;
+                let callback = self.callback_argument(1, env)?;
+                crate::reactor::register(callback, |emitter| {
+                    Ok(crate::timer::after(delay, emitter))
+                })?;
+                Ok(Value::Unit)
+            }
+            i if i.is(&["every"]) && env.get("every").is_none() => {
+                let interval = self.duration_argument(0, env)?;
+                if interval.is_zero() {
+                    return Err("every needs a positive interval".into());
+                }
+                let callback = self.callback_argument(1, env)?;
+                crate::reactor::register(callback, |emitter| {
+                    Ok(crate::timer::every(interval, emitter))
+                })?;
+                Ok(Value::Unit)
+            }
+            i if i.is(&["cron"]) && env.get("cron").is_none() => {
+                let schedule = crate::cron::Schedule::parse(&self.string_argument(0, env)?)
+                    .map_err(|e| Error::new("time", e))?;
+                // Schedules follow the wall clock in `--tz`, UTC by default.
+                let offset = match self.options.get("tz") {
+                    Some(tz) => {
+                        let tz = tz.evaluate(env)?;
+                        let tz = tz.try_get_string().ok_or(format!("{tz:?} is not string"))?;
+                        crate::time::parse_offset(&tz).map_err(|e| Error::new("time", e))?
+                    }
+                    None => 0,
+                };
+                let callback = self.callback_argument(1, env)?;
+                crate::reactor::register(callback, |emitter| {
+                    Ok(crate::timer::cron(schedule, offset, emitter))
+                })?;
+                Ok(Value::Unit)
+            }
+            i if (i.is(&["debounce"]) || i.is(&["throttle"])) && env.get(&i.path).is_none() => {
+                let window = self.duration_argument(0, env)?;
+                let kind = if i.path == "debounce" {
+                    crate::combinator::Kind::Debounce(window)
+                } else {
+                    crate::combinator::Kind::Throttle(window)
+                };
+                let f = self.callback_argument(1, env)?;
+                Ok(Value::Wrapped(crate::combinator::Wrapped::new(kind, f)))
+            }
+            i if i.is(&["batch"]) && env.get("batch").is_none() => {
+                let max = match self.options.get("max") {
+                    Some(max) => {
+                        let max = max.evaluate(env)?;
+                        let max = max
+                            .try_get_u64()
+                            .filter(|max| *max > 0)
+                            .ok_or(format!("batch --max {max:?} is not a positive int"))?;
+                        Some(max as usize)
+                    }
+                    None => None,
+                };
+                let within = match self.options.get("within") {
+                    Some(within) => match within.evaluate(env)? {
+                        Value::Duration(d) => Some(d.to_std().ok_or("negative --within")?),
+                        v => return Err(format!("batch --within {v:?} is not a duration").into()),
+                    },
+                    None => None,
+                };
+                if max.is_none() && within.is_none() {
+                    return Err("batch needs --max or --within".into());
+                }
+                let kind = crate::combinator::Kind::Batch { max, within };
+                let f = self.callback_argument(0, env)?;
+                Ok(Value::Wrapped(crate::combinator::Wrapped::new(kind, f)))
+            }
+            i if i.is(&["time", "now"]) => Ok(Value::DateTime(crate::time::DateTime::now())),
+            i if i.is(&["time", "parse"]) => {
+                let input = self.string_argument(0, env)?;
+                // Timestamps without an offset are read in `--tz`, UTC by default.
+                let offset = match self.options.get("tz") {
+                    Some(tz) => {
+                        let tz = tz.evaluate(env)?;
+                        let tz = tz.try_get_string().ok_or(format!("{tz:?} is not string"))?;
+                        crate::time::parse_offset(&tz).map_err(|e| Error::new("time", e))?
+                    }
+                    None => 0,
+                };
+                let parsed = match self.args.len() {
+                    1 => crate::time::DateTime::parse_rfc3339(&input),
+                    _ => {
+                        crate::time::DateTime::parse(&input, &self.string_argument(1, env)?, offset)
+                    }
+                };
+                parsed
+                    .map(Value::DateTime)
+                    .map_err(|e| Error::new("time", e))
+            }
+            i if i.is(&["env", "var"]) => {
+                let name = self.argument(0, env)?;
+                let name = name
                     .try_get_string()
-                    .ok_or("expected path string".to_string())?;
-                let f = File::open(f).map_err(|e| e.to_string())?;
+                    .ok_or(format!("{name:?} is not string"))?;
+                Ok(std::env::var(name)
+                    .map(Value::String)
+                    .unwrap_or(Value::None))
+            }
+            i if i.is(&["throw"]) && env.get("throw").is_none() => {
+                let error = self.error_argument(env)?;
+                Err(error)
+            }
+            i if i.is(&["error"]) && env.get("error").is_none() => {
+                let error = self.error_argument(env)?;
+                Ok(Value::Error(error))
+            }
+            _ => return Ok(None),
+        };
+
+        value.map(Some)
+    }
+
+    /// Builds the error for `throw` and `error`: either an error value, or a
+    /// message with an optional `--kind`.
+    fn error_argument(&self, env: &Environment) -> Result<Error, Error> {
+        let arg = self
+            .args
+            .last()
+            .ok_or("no arguments".to_string())?
+            .evaluate(env)?;
+        let kind = match self.options.get("kind") {
+            Some(kind) => {
+                let kind = kind.evaluate(env)?;
+                Some(
+                    kind.try_get_string()
+                        .ok_or(format!("{kind:?} is not string"))?,
+                )
+            }
+            None => None,
+        };
+        match arg {
+            Value::Error(mut e) => {
+                if let Some(kind) = kind {
+                    e.kind = kind;
+                }
+                Ok(e)
+            }
+            Value::String(message) => Ok(Error::new(kind.as_deref().unwrap_or("error"), message)),
+            arg => Err(format!("{arg:?} is not string").into()),
+        }
+    }
+
+    fn apply(&self, env: &Environment) -> TailResult {
+        let id = &self.fident;
+        let mut obj = env
+            .get(&id.path)
+            .ok_or(format!("no property {}", id.path))?;
+        let mut child = id.child.as_deref();
+        while let Some(c) = child {
+            if let Value::None = obj {
+                if c.safe {
+                    return Ok(Tail::Value(Value::None));
+                }
+                return Err(format!("cannot read {} of none", c.path).into());
+            }
+            obj = match obj.property(&c.path) {
+                Some(v) => v,
+                None if c.child.is_none() => {
+                    return self.call_method(&obj, &c.path, env).map(Tail::Value)
+                }
+                None => return Err(format!("no property {} in {obj:?}", c.path).into()),
+            };
+            child = c.child.as_deref();
+        }
 
-                let mut bufr = std::io::BufReader::new(f);
-                bufr.seek(SeekFrom::End(0));
+        match &obj {
+            // A bare name refers to the function itself unless it takes no parameters.
+            Value::Fn { params, .. } if !self.args.is_empty() || params.is_empty() => {
+                let args = self
+                    .args
+                    .iter()
+                    .map(|a| a.evaluate(env))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok(Tail::Call(obj, args))
+            }
+            Value::Wrapped(_) if !self.args.is_empty() => {
+                let args = self
+                    .args
+                    .iter()
+                    .map(|a| a.evaluate(env))
+                    .collect::<Result<Vec<_>, Error>>()?;
+                Ok(Tail::Call(obj, args))
+            }
+            _ => Ok(Tail::Value(obj)),
+        }
+    }
 
-                let cont = self
+    fn call_method(&self, obj: &Value, name: &str, env: &Environment) -> EvalResult {
+        match (obj, name) {
+            (Value::String(s), "includes") => {
+                let a = self
                     .args
                     .last()
                     .ok_or("no arguments".to_string())?
-                    .evaluate(env)?;
+                    .evaluate(env)?
+                    .try_get_string()
+                    .ok_or("not string".to_string())?;
+                let rg = regex::Regex::new(&a).map_err(|e| e.to_string())?;
 
-                let mut debouncer = notify_debouncer_full::new_debouncer(
-                    std::time::Duration::from_secs(1),
-                    None,
-                    tx,
-                )
-                .map_err(|e| e.to_string())?;
-
-                debouncer
-                    .watcher()
-                    .watch(
-                        std::path::Path::new("./latest.log"),
-                        notify::RecursiveMode::NonRecursive,
-                    )
-                    .map_err(|e| e.to_string())?;
-
-                for res in rx {
-                    match res {
-                        Ok(events) => {
-                            let includes_modify = events.iter().any(|f| match f.kind {
-                                EventKind::Modify(_) => true,
-                                _ => false,
-                            });
-
-                            if includes_modify {
-                                let mut string = String::new();
-                                bufr.read_to_string(&mut string).unwrap();
-                                string.lines().for_each(|line| {
-                                    // TODO error handling
-                                    let _ = cont
-                                        .try_evaluate_as_fn(vec![Value::String(line.to_string())])
-                                        .unwrap();
-                                });
-                            }
-                        }
-                        Err(e) => eprintln!("watch error: {:?}", e),
-                    }
+                let b = rg.is_match(s);
+                Ok(Value::Bool(b))
+            }
+            (Value::String(s), "find") => {
+                let pattern = self.argument(0, env)?;
+                let pattern = pattern
+                    .try_get_string()
+                    .ok_or(format!("{pattern:?} is not string"))?;
+                let rg = regex::Regex::new(&pattern).map_err(|e| e.to_string())?;
+                Ok(rg
+                    .find(s)
+                    .map(|m| Value::String(m.as_str().to_string()))
+                    .unwrap_or(Value::None))
+            }
+            (Value::Regex(re), "is_match") => {
+                Ok(Value::Bool(re.is_match(&self.string_argument(0, env)?)))
+            }
+            (Value::Regex(re), "captures") => {
+                let s = self.string_argument(0, env)?;
+                let Some(captures) = re.captures(&s) else {
+                    return Ok(Value::None);
+                };
+                let mut properties = Properties::new();
+                for name in re.capture_names().flatten() {
+                    let group = captures
+                        .name(name)
+                        .map(|m| Value::String(m.as_str().to_string()))
+                        .unwrap_or(Value::None);
+                    properties.set(name, group);
                 }
-
+                Ok(Value::Compound { properties })
+            }
+            (Value::Regex(re), "find_all") => {
+                let s = self.string_argument(0, env)?;
+                Ok(Value::List(
+                    re.find_iter(&s)
+                        .map(|m| Value::String(m.as_str().to_string()))
+                        .collect(),
+                ))
+            }
+            (Value::Regex(re), "replace") => {
+                let s = self.string_argument(0, env)?;
+                let replacement = self.string_argument(1, env)?;
+                Ok(Value::String(
+                    re.replace_all(&s, replacement.as_str()).into_owned(),
+                ))
+            }
+            (Value::Regex(re), "split") => {
+                let s = self.string_argument(0, env)?;
+                Ok(Value::List(
+                    re.split(&s).map(|s| Value::String(s.to_string())).collect(),
+                ))
+            }
+            (Value::Command(words), "lines") => {
+                crate::stream::process_lines(self.command(words, &self.args, env)?)
+                    .map(Value::Stream)
+            }
+            (Value::Command(words), "run") => {
+                let output = self
+                    .command(words, &self.args, env)?
+                    .output()
+                    .map_err(|e| Error::new("io", format!("{}: {e}", words[0])))?;
+                Ok(output_value(output))
+            }
+            // Starts the process, with the arguments before the callback, and
+            // calls back with what `.run` returns once it exits.
+            (Value::Command(words), "on_exit") => {
+                let last = self.args.len().checked_sub(1).ok_or("no arguments")?;
+                let callback = self.callback_argument(last, env)?;
+                let mut command = self.command(words, &self.args[..last], env)?;
+                command
+                    .stdout(std::process::Stdio::piped())
+                    .stderr(std::process::Stdio::piped());
+                let program = words[0].clone();
+                crate::reactor::register(callback, move |emitter| {
+                    let child = command
+                        .spawn()
+                        .map_err(|e| Error::new("io", format!("{program}: {e}")))?;
+                    let guard = KillOnDrop::new(child);
+                    let child = guard.0.clone();
+                    std::thread::spawn(move || match wait_with_output(&child) {
+                        Ok(output) => emitter.emit(output_value(output)),
+                        Err(e) => {
+                            emitter.fail(Error::new("io", format!("{program}: {e}")));
+                            false
+                        }
+                    });
+                    Ok(Box::new(guard))
+                })?;
+                Ok(Value::Unit)
+            }
+            (Value::DateTime(t), "format") => {
+                let format = self.string_argument(0, env)?;
+                t.format(&format)
+                    .map(Value::String)
+                    .map_err(|e| Error::new("time", e))
+            }
+            (Value::DateTime(t), "to_tz") => {
+                let tz = self.string_argument(0, env)?;
+                let offset = crate::time::parse_offset(&tz).map_err(|e| Error::new("time", e))?;
+                Ok(Value::DateTime(t.with_offset(offset)))
+            }
+            (Value::DateTime(t), "unix") => non_negative(t.unix()),
+            (Value::DateTime(t), field) if !matches!(t.field(field), Ok(None)) => {
+                let n = t.field(field).map_err(|e| Error::new("time", e))?;
+                n.map_or(Ok(Value::None), non_negative)
+            }
+            (Value::Duration(d), "seconds") => non_negative(d.nanos() / 1_000_000_000),
+            (Value::Duration(d), "millis") => non_negative(d.nanos() / 1_000_000),
+            (Value::Path(path), "join") => {
+                let child = self.argument(0, env)?;
+                let child = child
+                    .try_get_path()
+                    .ok_or(format!("cannot join {child:?} to a path"))?;
+                Ok(Value::Path(path.join(child)))
+            }
+            (Value::Path(path), "parent") => Ok(path
+                .parent()
+                .map(|p| Value::Path(p.to_path_buf()))
+                .unwrap_or(Value::None)),
+            (Value::Path(path), "file_name") => Ok(path
+                .file_name()
+                .map(|s| Value::String(s.to_string_lossy().into_owned()))
+                .unwrap_or(Value::None)),
+            (Value::Path(path), "extension") => Ok(path
+                .extension()
+                .map(|s| Value::String(s.to_string_lossy().into_owned()))
+                .unwrap_or(Value::None)),
+            (Value::Path(path), "with_extension") => {
+                let extension = self.string_argument(0, env)?;
+                Ok(Value::Path(path.with_extension(extension)))
+            }
+            (Value::Path(path), "canonicalize") => path
+                .canonicalize()
+                .map(Value::Path)
+                .map_err(|e| Error::new("io", format!("{}: {e}", path.display()))),
+            (Value::Path(path), "to_string") => {
+                Ok(Value::String(path.to_string_lossy().into_owned()))
+            }
+            (Value::List(xs), "len") => Ok(Value::UInt64(xs.len() as u64)),
+            (Value::List(xs), "is_empty") => Ok(Value::Bool(xs.is_empty())),
+            (Value::List(xs), "first") => Ok(xs.first().cloned().unwrap_or(Value::None)),
+            (Value::List(xs), "rest") => Ok(Value::List(xs.iter().skip(1).cloned().collect())),
+            (Value::List(xs), "push") => {
+                let mut xs = xs.clone();
+                xs.push(self.argument(0, env)?);
+                Ok(Value::List(xs))
+            }
+            (Value::List(xs), "get") => {
+                let index = self.argument(0, env)?;
+                let index = index.try_get_u64().ok_or(format!("{index:?} is not int"))?;
+                Ok(xs.get(index as usize).cloned().unwrap_or(Value::None))
+            }
+            // Indices past the end are dropped, like `get` returning none.
+            (Value::List(xs), "slice") => {
+                let range = self.range_argument(0, env)?;
+                Ok(Value::List(
+                    range
+                        .iter()
+                        .map_while(|i| xs.get(i as usize).cloned())
+                        .collect(),
+                ))
+            }
+            (Value::String(s), "slice") => {
+                let range = self.range_argument(0, env)?;
+                let chars: Vec<char> = s.chars().collect();
+                Ok(Value::String(
+                    range.iter().map_while(|i| chars.get(i as usize)).collect(),
+                ))
+            }
+            (Value::Range(r), "len") => match r.len() {
+                Some(len) => Ok(Value::UInt64(len)),
+                None => Err(Error::new(
+                    "range",
+                    format!("{r:?} has too many items to count"),
+                )),
+            },
+            (Value::Range(r), "is_empty") => Ok(Value::Bool(r.last_index().is_none())),
+            (Value::Range(r), "first") => Ok(r.get(0).map(Value::UInt64).unwrap_or(Value::None)),
+            (Value::Range(r), "rest") => Ok(Value::Range(r.rest())),
+            (Value::Range(r), "get") => {
+                let index = self.argument(0, env)?;
+                let index = index.try_get_u64().ok_or(format!("{index:?} is not int"))?;
+                Ok(r.get(index).map(Value::UInt64).unwrap_or(Value::None))
+            }
+            (Value::List(xs), "stream") => {
+                Ok(Value::Stream(Stream::from_values(xs.clone().into_iter())))
+            }
+            (Value::Range(r), "stream") => Ok(Value::Stream(Stream::from_values(
+                r.iter().map(Value::UInt64),
+            ))),
+            (Value::Stream(s), "map") => Ok(Value::Stream(s.clone().map(self.argument(0, env)?))),
+            (Value::Stream(s), "filter") => {
+                Ok(Value::Stream(s.clone().filter(self.argument(0, env)?)))
+            }
+            (Value::Stream(s), "take") => {
+                let n = self.argument(0, env)?;
+                let n = n.try_get_u64().ok_or(format!("{n:?} is not int"))?;
+                Ok(Value::Stream(s.clone().take(n)))
+            }
+            (Value::Stream(s), "chunk") => {
+                let n = self.argument(0, env)?;
+                let n = n
+                    .try_get_u64()
+                    .filter(|n| *n > 0)
+                    .ok_or(format!("chunk size {n:?} is not a positive int"))?;
+                Ok(Value::Stream(s.clone().chunk(n)))
+            }
+            (Value::Stream(s), "dedupe") => Ok(Value::Stream(s.clone().dedupe())),
+            (Value::Stream(s), "for_each") => s.clone().for_each(&self.argument(0, env)?),
+            (Value::Stream(s), "collect") => Ok(Value::List(s.clone().collect::<Result<_, _>>()?)),
+            (Value::Task(t), "join") => t.join(),
+            (Value::Task(t), "cancel") => {
+                t.cancel();
                 Ok(Value::Unit)
             }
+            (Value::Task(t), "is_done") => Ok(Value::Bool(t.is_done())),
+            (Value::Channel(c), "close") => {
+                c.close();
+                Ok(Value::Unit)
+            }
+            (Value::Channel(c), "stream") => Ok(Value::Stream(c.stream())),
+            (Value::Range(r), "to_list") => Ok(Value::List(r.iter().map(Value::UInt64).collect())),
+            _ => Err(format!("no property {name} in {obj:?}").into()),
+        }
+    }
 
-            id => {
-                let obj = env
-                    .get(&id.path)
-                    .ok_or(format!("no property {}", id.path))?;
-                let a = match obj {
-                    Value::String(s) => {
-                        if id.child.is_some_and(|i| i.path == "includes".to_string()) {
-                            let a = self
-                                .args
-                                .last()
-                                .ok_or("no arguments".to_string())?
-                                .evaluate(env)?
-                                .try_get_string()
-                                .ok_or("not string".to_string())?;
-                            let rg = regex::Regex::new(&a).map_err(|e| e.to_string())?;
-
-                            let b = rg.is_match(s);
-                            Ok(Value::Bool(b))
-                        } else {
-                            Ok(obj.clone())
-                        }
-                    }
-                    _ => Ok(obj.clone()),
-                };
+    fn argument(&self, index: usize, env: &Environment) -> EvalResult {
+        self.args
+            .get(index)
+            .ok_or(format!("missing argument {}", index + 1))?
+            .evaluate(env)
+    }
 
-                a
+    /// The process for `words`, with `args` evaluated as further arguments.
+    fn command(
+        &self,
+        words: &[String],
+        args: &[PrimaryExpr],
+        env: &Environment,
+    ) -> Result<std::process::Command, Error> {
+        let mut command = std::process::Command::new(&words[0]);
+        command.args(&words[1..]);
+        for arg in args {
+            match arg.evaluate(env)? {
+                Value::String(s) => command.arg(s),
+                Value::Path(path) => command.arg(path),
+                Value::UInt64(n) => command.arg(n.to_string()),
+                v => return Err(format!("{v:?} is not a command argument").into()),
+            };
+        }
+        Ok(command)
+    }
+
+    /// A function argument; a block literal becomes a function of no
+    /// parameters, run when it is called rather than right away. A
+    /// parenthesized `fn` literal is just the function.
+    fn callback_argument(&self, index: usize, env: &Environment) -> EvalResult {
+        match self.args.get(index) {
+            Some(PrimaryExpr::Block(body)) if !body.is_function_literal() => Ok(Value::Fn {
+                env: env.clone(),
+                body: body.clone(),
+                params: Vec::new(),
+                name: None,
+            }),
+            _ => self.argument(index, env),
+        }
+    }
+
+    fn duration_argument(
+        &self,
+        index: usize,
+        env: &Environment,
+    ) -> Result<std::time::Duration, Error> {
+        match self.argument(index, env)? {
+            Value::Duration(d) => d.to_std().ok_or(format!("negative duration {d}").into()),
+            value => Err(format!("{value:?} is not a duration").into()),
+        }
+    }
+
+    fn channel_argument(&self, index: usize, env: &Environment) -> Result<Channel, Error> {
+        match self.argument(index, env)? {
+            Value::Channel(channel) => Ok(channel),
+            value => Err(format!("{value:?} is not a channel").into()),
+        }
+    }
+
+    fn range_argument(&self, index: usize, env: &Environment) -> Result<IntRange, Error> {
+        match self.argument(index, env)? {
+            Value::Range(range) => Ok(range),
+            value => Err(format!("{value:?} is not a range").into()),
+        }
+    }
+
+    fn string_argument(&self, index: usize, env: &Environment) -> Result<String, Error> {
+        let value = self.argument(index, env)?;
+        value
+            .try_get_string()
+            .ok_or(format!("{value:?} is not string").into())
+    }
+}
+
+/// What `.run` returns for a finished process.
+fn output_value(output: std::process::Output) -> Value {
+    let mut properties = Properties::new();
+    let status = output
+        .status
+        .code()
+        .map(|c| Value::UInt64(c as u64))
+        .unwrap_or(Value::None);
+    properties.set("status", status);
+    let text = |bytes: Vec<u8>| Value::String(String::from_utf8_lossy(&bytes).into_owned());
+    properties.set("stdout", text(output.stdout));
+    properties.set("stderr", text(output.stderr));
+    Value::Compound { properties }
+}
+
+/// Kills a child process on drop unless it has already exited, so that
+/// shutting down the event loop doesn't leave it running. The child is only
+/// reaped through here, so its pid can't have been reused when it is killed.
+struct KillOnDrop(Arc<std::sync::Mutex<std::process::Child>>);
+
+impl KillOnDrop {
+    fn new(child: std::process::Child) -> KillOnDrop {
+        KillOnDrop(Arc::new(std::sync::Mutex::new(child)))
+    }
+}
+
+impl Drop for KillOnDrop {
+    fn drop(&mut self) {
+        let mut child = self.0.lock().unwrap();
+        if let Ok(None) = child.try_wait() {
+            // SAFETY: plain syscall; the child hasn't been reaped, so the pid is still its.
+            unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+        }
+    }
+}
+
+/// Like `Child::wait_with_output`, but without holding on to the child, so
+/// that `KillOnDrop` can still signal it meanwhile.
+fn wait_with_output(
+    child: &std::sync::Mutex<std::process::Child>,
+) -> std::io::Result<std::process::Output> {
+    fn read_all(
+        pipe: Option<impl std::io::Read + Send + 'static>,
+    ) -> std::thread::JoinHandle<Vec<u8>> {
+        std::thread::spawn(move || {
+            let mut bytes = Vec::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_end(&mut bytes);
             }
+            bytes
+        })
+    }
+    let (stdout, stderr) = {
+        let mut child = child.lock().unwrap();
+        (read_all(child.stdout.take()), read_all(child.stderr.take()))
+    };
+    let status = loop {
+        if let Some(status) = child.lock().unwrap().try_wait()? {
+            break status;
         }
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    };
+    Ok(std::process::Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
+
+impl Range {
+    fn evaluate(&self, env: &Environment) -> EvalResult {
+        let int = |e: &Expr| {
+            let v = e.evaluate(env)?;
+            v.try_get_u64()
+                .ok_or(Error::from(format!("range bound {v:?} is not int")))
+        };
+        let step = match &self.step {
+            Some(step) => {
+                let v = step.evaluate(env)?;
+                v.try_get_u64()
+                    .filter(|n| *n > 0)
+                    .ok_or(format!("range step {v:?} is not a positive int"))?
+            }
+            None => 1,
+        };
+        Ok(Value::Range(IntRange {
+            start: int(&self.start)?,
+            end: int(&self.end)?,
+            inclusive: self.inclusive,
+            step,
+        }))
     }
 }
 
+/// Integers are unsigned, so negative results are an error rather than wrapping.
+fn non_negative(n: i64) -> EvalResult {
+    u64::try_from(n)
+        .map(Value::UInt64)
+        .map_err(|_| format!("{n} is negative").into())
+}
+
 impl Expr {
     pub fn evaluate(&self, env: &Environment) -> EvalResult {
+        self.evaluate_tail(env)?.resolve()
+    }
+
+    fn evaluate_tail(&self, env: &Environment) -> TailResult {
         match self {
-            Expr::AddSub(e) => e.evaluate(env),
-            Expr::MulDiv(e) => e.evaluate(env),
-            Expr::Primary(e) => e.evaluate(env),
-            Expr::FunctionApplication(e) => e.evaluate(env),
+            Expr::AddSub(e) => e.evaluate(env).map(Tail::Value),
+            Expr::MulDiv(e) => e.evaluate(env).map(Tail::Value),
+            Expr::Compare(e) => e.evaluate(env).map(Tail::Value),
+            Expr::Primary(e) => e.evaluate_tail(env),
+            Expr::FunctionApplication(e) => e.evaluate_tail(env),
             Expr::If(e) => {
                 let cond = e.cond.evaluate(env)?;
-                let result = if cond.try_get_bool().ok_or(format!("{cond:?} is not bool"))? {
-                    e.true_exp.evaluate(env)?
+                if cond.try_get_bool().ok_or(format!("{cond:?} is not bool"))? {
+                    e.true_exp.evaluate_tail(env)
                 } else {
-                    e.false_expr.evaluate(env)?
+                    e.false_expr.evaluate_tail(env)
+                }
+            }
+            Expr::Try(e) => {
+                let registered = crate::reactor::registered();
+                let result = match (e.body.evaluate(env), &e.catch) {
+                    // Cancellation can't be caught, only cleaned up after.
+                    (Err(error), Some(catch)) if !cancellation_pending() => catch
+                        .handler
+                        .evaluate_tail(&env.set(&catch.name, Value::Error(error))),
+                    (result, _) => result.map(Tail::Value),
                 };
-                Ok(result)
+                match &e.finally {
+                    Some(finally) => run_deferred(result, vec![(env.clone(), finally)], registered),
+                    None => result,
+                }
             }
+            Expr::Range(e) => e.evaluate(env).map(Tail::Value),
+            Expr::Coalesce(e) => match e.value.evaluate(env)? {
+                Value::None => e.default.evaluate_tail(env),
+                v => Ok(Tail::Value(v)),
+            },
+            Expr::Propagate(e) => match e.evaluate(env)? {
+                Value::Error(error) => Err(error),
+                v => Ok(Tail::Value(v)),
+            },
         }
     }
 }
+
+#[cfg(test)]
+fn run(source: &str) -> EvalResult {
+    let source = source.to_string();
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let (_, block) = crate::parser::parse_file(&source).map_err(|e| e.to_string())?;
+            block.run(&Environment::new())
+        })
+        .unwrap()
+        .join()
+        .unwrap()
+}
+
+#[test]
+fn test_tail_call() {
+    let v = run("fn down(n) { if n == 0 then {true} else {down (n - 1)} }\ndown 100000").unwrap();
+    assert!(matches!(v, Value::Bool(true)));
+
+    // Lists and compounds compare by value, e.g. to end a recursion.
+    let v = run(
+        "fn drop(xs, n) { if n == 0 then {xs} else {drop (xs.rest) (n - 1)} }
+let xs = [1, 2, 3]
+[(drop xs 2) == [3], (drop xs 1) != [2, 3], (a=1, b=[2]) == (a=1, b=[2]), [1] == [2]]",
+    )
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        "[true,false,true,false]"
+    );
+    let e = run("[1] < [2]").unwrap_err();
+    assert!(e.message.contains("cannot compare"), "{e}");
+}
+
+#[test]
+fn test_recursion_limit() {
+    let e = run("fn deep(n) { let r = deep (n + 1); r }\ndeep 0").unwrap_err();
+    assert_eq!(e.kind, "recursion");
+
+    let v = run("fn deep(n) { let r = deep (n + 1); r }\ntry {deep 0} catch e {e.kind}").unwrap();
+    assert!(matches!(v, Value::String(kind) if kind == "recursion"));
+}
+
+#[test]
+fn test_comparison() {
+    let v = run("[1 < 2, 2 <= 2, 3 > 4, 3 >= 4, 1 + 1 == 2, \"a\" != \"b\"]").unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        "[true,true,false,false,true,true]"
+    );
+}
+
+#[test]
+fn test_checked_arithmetic() {
+    let v = run("[2 * 3 + 1, 7 / 2, 10 - 4]").unwrap();
+    assert_eq!(serde_json::to_string(&v).unwrap(), "[7,3,6]");
+
+    for (source, message) in [
+        ("0 - 1", "integer overflow"),
+        ("18446744073709551615 + 1", "integer overflow"),
+        ("4294967296 * 4294967296", "integer overflow"),
+        ("1 / 0", "division by zero"),
+    ] {
+        let e = run(source).unwrap_err();
+        assert!(e.message.contains(message), "{source}: {e}");
+    }
+}
+
+#[test]
+fn test_fn_declaration() {
+    let v = run("fn add(a, b) { a + b }
+fn answer() { add 40 2 }
+let double = fn(x) { x * 2 }
+[add 1 2, answer, double 4, (fn() { 5 })]")
+    .unwrap();
+    let Value::List(items) = v else {
+        panic!("not a list: {v:?}")
+    };
+    assert_eq!(serde_json::to_string(&items[..3]).unwrap(), "[3,42,8]");
+    // A fn literal is only called when applied.
+    assert!(matches!(items[3], Value::Fn { .. }), "{:?}", items[3]);
+
+    let e = run("fn add(a, b) { a + b }\nadd 1").unwrap_err();
+    assert!(e.message.contains("Expected 2 arguments but got 1"), "{e}");
+}
+
+#[test]
+fn test_mutable_binding() {
+    let v = run("let mut n = 0\nfn bump() { n = n + 1 }\nbump; bump; n").unwrap();
+    assert!(matches!(v, Value::UInt64(2)));
+
+    let e = run("let n = 0; n = 1").unwrap_err();
+    assert!(e.message.contains("immutable"), "{e}");
+}
+
+#[test]
+fn test_prelude() {
+    let env = Environment::new().with_prelude().unwrap();
+    let (_, block) = crate::parser::parse_file(
+        "let xs = map (fn(x) {x * 2}) [1, 2, 3]\nfilter (fn(x) {x > 2}) xs",
+    )
+    .unwrap();
+    let v = block.evaluate(&env).unwrap();
+    assert_eq!(serde_json::to_string(&v).unwrap(), "[4,6]");
+
+    // Large lists don't take quadratic time.
+    let (_, block) =
+        crate::parser::parse_file("filter (fn(x) {x > 19998}) (map (fn(x) {x + 1}) (0..20000))")
+            .unwrap();
+    let v = block.evaluate(&env).unwrap();
+    assert_eq!(serde_json::to_string(&v).unwrap(), "[19999,20000]");
+
+    // `twice` is a builtin, so it works without the prelude too.
+    let v = run("let mut n = 0\nfn bump(u) { n = n + 1 }\ntwice bump\nn").unwrap();
+    assert!(matches!(v, Value::UInt64(2)));
+}
+
+#[test]
+fn test_destructuring() {
+    let v = run("let (user=u, action) = (user=\"a\", action=\"joined\")\nlet [first, ..rest] = [1, 2, 3]\nfn f([x, y], (n=z)) { x + y + z }\nf rest (n=first)").unwrap();
+    assert!(matches!(v, Value::UInt64(6)));
+
+    let e = run("let [a, b] = [1]").unwrap_err();
+    assert_eq!(e.kind, "pattern");
+    let e = run("let (user=u) = (name=1)").unwrap_err();
+    assert_eq!(e.kind, "pattern");
+}
+
+#[test]
+fn test_annotations() {
+    let v = run("let n: Int = 1\nfn send(url: String, msg: (content: String, retries: Int?)) { msg.content }\nsend \"x\" (content=\"hi\", extra=n)").unwrap();
+    assert!(matches!(v, Value::String(s) if s == "hi"));
+
+    let e = run("let n: String = 1").unwrap_err();
+    assert_eq!(e.kind, "type");
+    let e = run("fn send(msg: (content: String)) { msg }\nsend (text=\"hi\")").unwrap_err();
+    assert_eq!(e.kind, "type");
+    let e = run("let xs: [Int] = [1, \"a\"]").unwrap_err();
+    assert_eq!(e.kind, "type");
+    assert!(run("let r: Range = 0..3\nlet d: Duration = 5s").is_ok());
+}
+
+#[test]
+fn test_regex() {
+    let v = run(r#"let re = re"(?P<user>\w+) (?P<action>joined|left)"
+let digits = re"\d+"
+let comma = re"\s*,\s*"
+let digit = re"(?P<n>\d)"
+[re.is_match "a joined", re.captures "bob left", re.captures "nothing", digits.find_all "a1b22", comma.split "a , b,c", digit.replace "a1b2" "<$n>"]"#).unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[true,{"action":"left","user":"bob"},null,["1","22"],["a","b","c"],"a<1>b<2>"]"#
+    );
+    assert!(crate::parser::parse_file("re\"(\"").is_err());
+}
+
+#[test]
+fn test_tagged_literals() {
+    let v = run(
+        r#"let payload = json"{\"user\": \"a\", \"ids\": [1, 2], \"ok\": true, \"extra\": null}"
+let hook = url"https://example.com:8443/hook?x=1"
+let log = path"/var/log/app.log"
+let echo = sh"echo 'hello world'"
+let out = echo.run
+[payload, hook.host, hook.port, hook.path, hook.query, log, out.stdout]"#,
+    )
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[{"extra":null,"ids":[1,2],"ok":true,"user":"a"},"example.com",8443,"/hook","x=1","/var/log/app.log","hello world\n"]"#
+    );
+
+    for invalid in [
+        r#"json"{""#,
+        r#"url"example""#,
+        r#"sh"echo 'a""#,
+        r#"yaml"a""#,
+    ] {
+        assert!(crate::parser::parse_file(invalid).is_err(), "{invalid}");
+    }
+}
+
+#[test]
+fn test_path() {
+    let v = run(r#"let log = path"/var/log" / "app" / "latest.log"
+let parent = log.parent
+let name = log.file_name
+let ext = log.extension
+let gz = log.with_extension "gz"
+let joined = parent.join "old.log"
+let root = path"/"
+let ls = sh"ls -d"
+let out = ls.run root
+[log, parent, name, ext, gz, joined, root.parent, out.stdout]"#)
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"["/var/log/app/latest.log","/var/log/app","latest.log","log","/var/log/app/latest.gz","/var/log/app/old.log",null,"/\n"]"#
+    );
+    let cwd = run("fs.cwd").unwrap();
+    assert!(matches!(cwd, Value::Path(p) if p == std::env::current_dir().unwrap()));
+}
+
+#[test]
+fn test_time() {
+    let v = run(
+        r#"let seen = time.parse "2024-03-01 08:59:00" "%Y-%m-%d %H:%M:%S" --tz "+09:00"
+let now = time.parse "2024-03-01T00:00:00Z"
+let age = now - seen
+let local = now.to_tz "+09:00"
+[age, age > 5m, seen + 90s, local.format "%H:%M %Z", local.hour, 2m * 3 - 30s, age.seconds]"#,
+    )
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"["1m",false,"2024-03-01T09:00:30+09:00","09:00 +09:00",9,"5m30s",60]"#
+    );
+
+    let e = run("time.parse \"yesterday\"").unwrap_err();
+    assert_eq!(e.kind, "time");
+}
+
+#[test]
+fn test_range() {
+    let env = Environment::new().with_prelude().unwrap();
+    let (_, block) = crate::parser::parse_file(r#"let r = 1..=10 --step 3
+let xs = ["a", "b", "c", "d"]
+let s = "hello"
+let mut n = 0
+each (fn(i) { n = n + i }) (0..5)
+[r, r.len, r.get 3, r.get 4, r.rest, 5..2, xs.slice (1..3), xs.slice (0..10 --step 2), s.slice (1..=3), map (fn(i) { i * i }) (0..4), n]"#)
+    .unwrap();
+    let v = block.evaluate(&env).unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[[1,4,7,10],4,10,null,[4,7,10],[],["b","c"],["a","c"],"ell",[0,1,4,9],10]"#
+    );
+
+    let big = run("let r = 0..1000000000000\nr.get 999999999999").unwrap();
+    assert!(matches!(big, Value::UInt64(999999999999)));
+    assert!(run("0..3 --step 0").is_err());
+    let e = run("let r = 0..=18446744073709551615\nr.len").unwrap_err();
+    assert_eq!(e.kind, "range");
+    let last = run("let r = 0..=18446744073709551615\nlet t = r.rest\nt.get 18446744073709551614")
+        .unwrap();
+    assert!(matches!(last, Value::UInt64(u64::MAX)));
+}
+
+#[test]
+fn test_stream() {
+    let v = run(r#"let xs = [1, 1, 2, 3, 3, 3, 4, 5, 6, 7]
+let s = xs.stream
+let s = s.dedupe
+let s = s.map (fn(x) { x * 10 })
+let s = s.filter (fn(x) { x != 30 })
+let s = s.take 4
+let s = s.chunk 3
+let mut seen = []
+let r = 0..3
+let rs = r.stream
+rs.for_each (fn(x) { seen = seen.push x })
+let echo = sh"printf 'a
+b
+'"
+let lines = echo.lines
+[s.collect, seen, lines.collect]"#)
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[[[10,20,40],[50]],[0,1,2],["a","b"]]"#
+    );
+    // A callback may read the stream it belongs to.
+    let v = run(r#"let xs = [1, 2, 3]
+let src = xs.stream
+let mut s = none
+s = src.map (fn(x) { let next = s.take 1; [x, next.collect] })
+s.collect"#)
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[[1,[[2,[[3,[]]]]]]]"#
+    );
+}
+
+#[test]
+fn test_defer() {
+    let v = run(r#"let mut log = []
+fn step(name) { log = log.push name }
+fn work(fail) {
+  step "start"
+  defer { step "cleanup 1" }
+  defer { step "cleanup 2" }
+  if fail then { throw "boom" } else { step "done" }
+}
+work false
+try { work true } catch e { step (e.message) }
+try { step "body" } finally { step "finally" }
+try { try { throw "inner" } finally { step "finally 2" } } catch e { step (e.message) }
+log"#)
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"["start","done","cleanup 2","cleanup 1","start","cleanup 2","cleanup 1","boom","body","finally","finally 2","inner"]"#
+    );
+
+    let v = run("let mut n = 0\nfn f() { defer { n = n + 1 }; 5 }\nlet x = f\n[x, n]").unwrap();
+    assert_eq!(serde_json::to_string(&v).unwrap(), "[5,1]");
+}
+
+#[test]
+fn test_defer_event_loop() {
+    // Cleanups of a block that registered callbacks wait for them.
+    let v = run(r#"let mut log = []
+fn note(x) { log = log.push x }
+let t = spawn {
+  defer { note "deferred" }
+  after 100ms { note "after" }
+  try { after 50ms { note "after 2" } } finally { note "finally" }
+  note "registered"
+}
+join t
+log"#)
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"["registered","after 2","after","finally","deferred"]"#
+    );
+
+    let tmp = std::env::temp_dir().join(format!("haksh-defer-{}", std::process::id()));
+    std::fs::create_dir_all(&tmp).unwrap();
+    let dir = tmp.display();
+    let v = run(&format!(
+        r#"let c = chan
+let t = spawn {{
+  defer {{ send c "watcher stopped" }}
+  using line = fs.watch "{dir}/app.log"
+  send c line
+}}
+sleep 300ms
+let append = sh"sh -c 'echo one >> {dir}/app.log'"
+append.run
+let first = recv c --timeout 5s
+t.cancel
+let second = recv c --timeout 5s
+[first, second]"#
+    ))
+    .unwrap();
+    std::fs::remove_dir_all(&tmp).unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"["one","watcher stopped"]"#
+    );
+}
+
+#[test]
+fn test_defer_cancelled() {
+    // Cancelling the event loop runs the cleanups, after the last callback.
+    let v = run(r#"let mut log = []
+fn note(x) { log = log.push x }
+let c = chan
+let t = spawn {
+  defer { note "deferred" }
+  try {
+    every 50ms { note "tick"; send c "tick" }
+  } finally { note "finally" }
+}
+recv c --timeout 5s
+t.cancel
+try { join t } catch e { note (e.kind) }
+log"#)
+    .unwrap();
+    let log = serde_json::to_value(&v).unwrap();
+    let log: Vec<_> = log
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|l| l.as_str().unwrap())
+        .collect();
+    let (ticks, rest) = log.split_at(log.len() - 3);
+    assert!(
+        !ticks.is_empty() && ticks.iter().all(|l| *l == "tick"),
+        "{log:?}"
+    );
+    assert_eq!(rest, ["finally", "deferred", "cancelled"]);
+}
+
+#[test]
+fn test_compound_update() {
+    let v = run(
+        "let cfg = (url=\"x\", http=(timeout=1, retries=3))\n(..cfg, content=\"hi\", http.timeout=5)",
+    )
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"{"content":"hi","http":{"retries":3,"timeout":5},"url":"x"}"#
+    );
+}
+
+#[test]
+fn test_optional() {
+    let v = run("let e = (user=(name=\"a\"))\n[e.action, e.user?.name, e.action?.name, e.action ?? \"left\", e.user.name ?? \"b\"]").unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[null,"a",null,"left","a"]"#
+    );
+
+    let e = run("let e = (user=1)\ne.action.name").unwrap_err();
+    assert!(e.message.contains("of none"), "{e}");
+
+    let v = run("let s = \"abc\"\n(s.find \"x\") == none").unwrap();
+    assert!(matches!(v, Value::Bool(true)));
+}
+
+#[test]
+fn test_try_catch() {
+    let v = run(r#"try {throw --kind "http" "boom"} catch e {e.message}"#).unwrap();
+    assert!(matches!(v, Value::String(m) if m == "boom"));
+
+    let e = run(r#"let e = error "bad"; println "before"; e?"#).unwrap_err();
+    assert_eq!(e, Error::new("error", "bad"));
+
+    let v = run(r#"try {1 + "a"} catch e {e.kind}"#).unwrap();
+    assert!(matches!(v, Value::String(kind) if kind == "runtime"));
+
+    // The names are free for scripts to use.
+    let v = run("let error = \"x\"\nerror").unwrap();
+    assert!(matches!(v, Value::String(s) if s == "x"));
+    let v = run("fn throw(x) { x }\nthrow 1").unwrap();
+    assert!(matches!(v, Value::UInt64(1)));
+}
+
+#[test]
+fn test_import() {
+    let dir = std::env::temp_dir().join(format!("haksh-test-import-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("lib")).unwrap();
+    std::fs::write(
+        dir.join("lib/discord.haksh"),
+        "let prefix = \"hi \"\nexport fn send(n) { n + 1 }\nexport let name = \"discord\"",
+    )
+    .unwrap();
+    std::fs::write(dir.join("a.haksh"), "import \"./b.haksh\" as b").unwrap();
+    std::fs::write(dir.join("b.haksh"), "import \"./a.haksh\" as a").unwrap();
+
+    let env = Environment::new().in_file(&dir.join("main.haksh"));
+    let (_, block) =
+        crate::parser::parse_file("import \"./lib/discord.haksh\" as discord\ndiscord.send 1")
+            .unwrap();
+    assert!(matches!(block.evaluate(&env), Ok(Value::UInt64(2))));
+
+    let (_, block) = crate::parser::parse_file("import \"./a.haksh\" as a").unwrap();
+    let e = block.evaluate(&env).unwrap_err();
+    assert!(e.message.contains("import cycle"), "{e}");
+
+    // Tasks importing a module at the same time are not a cycle, and the
+    // second waits for the first to evaluate it.
+    let count = dir.join("count");
+    std::fs::write(
+        dir.join("slow.haksh"),
+        format!(
+            "let mark = sh\"sh -c 'echo x >> {}'\"\nmark.run\nsleep 300ms\nexport let n = 1",
+            count.display()
+        ),
+    )
+    .unwrap();
+    let (_, block) = crate::parser::parse_file(
+        "let load = fn() { import \"./slow.haksh\" as slow; slow.n }\njoin [spawn load, spawn load]",
+    )
+    .unwrap();
+    let v = block.evaluate(&env).unwrap();
+    assert_eq!(serde_json::to_string(&v).unwrap(), "[1,1]");
+    assert_eq!(std::fs::read_to_string(&count).unwrap(), "x\n");
+
+    // Nor do they wait for each other forever when their modules form a cycle.
+    std::fs::write(
+        dir.join("c.haksh"),
+        "sleep 200ms\nimport \"./d.haksh\" as d",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("d.haksh"),
+        "sleep 200ms\nimport \"./c.haksh\" as c",
+    )
+    .unwrap();
+    let (_, block) = crate::parser::parse_file(
+        "fn load(f) { spawn { try { f; \"loaded\" } catch e { e.message } } }
+let c = load (fn() { import \"./c.haksh\" as c })
+let d = load (fn() { import \"./d.haksh\" as d })
+join [c, d]",
+    )
+    .unwrap();
+    let v = block.evaluate(&env).unwrap();
+    let Value::List(messages) = v else {
+        panic!("not a list: {v:?}")
+    };
+    for message in messages {
+        assert!(
+            matches!(&message, Value::String(m) if m.contains("import cycle")),
+            "{message:?}"
+        );
+    }
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_tasks() {
+    let v = run(r#"let c = chan
+let producer = spawn {
+  send c 1
+  send c 2
+  c.close
+  "sent"
+}
+let doubled = spawn {
+  let s = c.stream
+  let s = s.map (fn(x) { x * 2 })
+  s.collect
+}
+let quiet = chan
+let failing = spawn { throw "boom" }
+let stuck = spawn { recv quiet }
+stuck.cancel
+let cancelled = try { join stuck } catch e { e.kind }
+let failed = try { join failing } catch e { e.message }
+[join [producer, doubled], recv quiet --timeout 10ms, failed, cancelled]"#)
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[["sent",[2,4]],null,"boom","cancelled"]"#
+    );
+}
+
+#[test]
+fn test_task_cancellation() {
+    let v = run(r#"let mut log = []
+fn note(x) { log = log.push x }
+let t = spawn {
+  defer { note "deferred" }
+  let r = try { sleep 60s } catch e { e.kind }
+  note r
+  sleep 60s
+}
+let ticker = spawn {
+  every 50ms { try { sleep 60s } catch e { note "caught" } }
+}
+sleep 200ms
+t.cancel
+ticker.cancel
+let started = time.now
+let results = [try { join t } catch e { e.kind }, try { join ticker } catch e { e.kind }]
+let now = time.now
+let waited = now - started
+let seconds = waited.seconds
+[results, log, seconds < 30]"#)
+    .unwrap();
+    // Cancelling interrupts the sleeps rather than waiting them out.
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[["cancelled","cancelled"],["deferred"],true]"#
+    );
+}
+
+/// A port nothing is listening on right now.
+#[cfg(test)]
+fn free_port() -> u16 {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().port()
+}
+
+#[test]
+fn test_event_loop() {
+    let port = free_port();
+    let v = run(&r#"let mut log = []
+let t = spawn {
+  let slow = sh"sh -c 'sleep 0.3; echo slow'"
+  let echo = sh"echo"
+  slow.on_exit (fn(r) { log = log.push (r.stdout) })
+  echo.on_exit "fast" (fn(r) { log = log.push (r.stdout) })
+  log = log.push "registered"
+}
+join t
+let server = spawn {
+  http.serve PORT (fn(req) {
+    let path = req.path
+    (method=req.method, path=path)
+  })
+}
+let wait = chan
+recv wait --timeout 200ms
+let body = http.get "http://127.0.0.1:PORT/hi"
+server.cancel
+let stopped = try { join server } catch e { e.kind }
+[log, body, stopped]"#
+        .replace("PORT", &port.to_string()))
+    .unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[["registered","fast\n","slow\n"],"{\"method\":\"GET\",\"path\":\"/hi\"}","cancelled"]"#
+    );
+}
+
+#[test]
+fn test_http_serve() {
+    use std::io::{Read, Write};
+    let port = free_port();
+    let server = std::thread::spawn(move || {
+        run(&format!(
+            "let server = spawn {{ http.serve {port} (fn(req) {{ req.body }}) }}\nsleep 1s\nserver.cancel"
+        ))
+    });
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    let request = |text: &str| {
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(text.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    // A client that sends nothing doesn't hold up the others.
+    let _idle = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+    let ok = request("POST / HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi");
+    assert!(
+        ok.starts_with("HTTP/1.1 200 OK") && ok.ends_with("\r\n\r\nhi"),
+        "{ok}"
+    );
+    let large = request("POST / HTTP/1.1\r\nContent-Length: 99999999999999999\r\n\r\n");
+    assert!(large.starts_with("HTTP/1.1 413"), "{large}");
+    server.join().unwrap().unwrap();
+}
+
+#[test]
+fn test_timers() {
+    let v = run(r#"let c = chan
+let t = spawn {
+  after 500ms { send c "after" }
+  every 50ms { send c "tick" }
+  cron "0 0 1 1 *" { send c "new year" }
+}
+fn ticks_before_after(ticks) {
+  let event = recv c --timeout 5s
+  if event == none then { throw "timed out" } else { }
+  if event == "after" then { ticks } else { ticks_before_after (ticks + 1) }
+}
+let ticks = ticks_before_after 0
+let next = recv c --timeout 5s
+t.cancel
+let stopped = try { join t } catch e { e.kind }
+[ticks > 0, next, stopped]"#)
+    .unwrap();
+    // The ticker keeps going after the one-off timer has fired.
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[true,"tick","cancelled"]"#
+    );
+
+    // The names are free for scripts to use.
+    let v = run("fn every(n) { n * 2 }\nlet after = 3\nevery after").unwrap();
+    assert!(matches!(v, Value::UInt64(6)));
+}
+
+#[test]
+fn test_combinators() {
+    let v = run(r#"let mut log = []
+let t = spawn {
+  let note = debounce 150ms (fn(x) { log = log.push x })
+  note "a"
+  note "b"
+  let once = throttle 1h (fn(x) { log = log.push x })
+  once "first"
+  once "dropped"
+  let post = batch --max 3 --within 250ms (fn(xs) { log = log.push xs })
+  post 1
+  post 2
+  post 3
+  post 4
+}
+join t
+let stopped = spawn {
+  let note = debounce 1h (fn(x) { log = log.push x })
+  note "last"
+  let post = batch --max 10 (fn(xs) { log = log.push xs })
+  post 5
+}
+sleep 100ms
+stopped.cancel
+try { join stopped } catch e { }
+log"#)
+    .unwrap();
+    // Calls still pending when the event loop stops are made then.
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"["first",[1,2,3],"b",[4],"last",[5]]"#
+    );
+
+    // The names are free for scripts to use.
+    let v = run("let batch = 3\nfn debounce(n) { n + batch }\ndebounce batch").unwrap();
+    assert!(matches!(v, Value::UInt64(6)));
+}
+
+#[test]
+fn test_watch() {
+    let tmp = std::env::temp_dir().join(format!("haksh-watch-{}", std::process::id()));
+    std::fs::create_dir_all(&tmp).unwrap();
+    std::fs::write(tmp.join("a.log"), "old\n").unwrap();
+    let dir = tmp.display();
+    let v = run(&format!(
+        r#"let c = chan
+let t = spawn {{
+  fs.watch "{dir}/*.log" (fn(line, path, kind) {{ send c [line, path.file_name, kind] }})
+}}
+sleep 300ms
+let append = sh"sh -c 'echo one >> {dir}/a.log; echo two > {dir}/b.log; echo no >> {dir}/c.txt'"
+append.run
+let first = recv c --timeout 5s
+let second = recv c --timeout 5s
+let third = recv c --timeout 1500ms
+t.cancel
+[first, second, third]"#
+    ))
+    .unwrap();
+    std::fs::remove_dir_all(&tmp).unwrap();
+    let mut lines = serde_json::to_value(&v).unwrap();
+    assert_eq!(lines[2], serde_json::Value::Null);
+    let lines = lines.as_array_mut().unwrap();
+    lines.pop();
+    lines.sort_by_key(|l| l.to_string());
+    assert_eq!(
+        serde_json::to_string(&lines).unwrap(),
+        r#"[["one","a.log","modify"],["two","b.log","create"]]"#
+    );
+}
+
+#[test]
+fn test_watch_callbacks() {
+    let tmp = std::env::temp_dir().join(format!("haksh-callbacks-{}", std::process::id()));
+    std::fs::create_dir_all(&tmp).unwrap();
+    let dir = tmp.display();
+    let v = run(&format!(
+        r#"let changed = chan
+let c = chan
+let t = spawn {{
+  fs.watch "{dir}/a.log" {{ send changed "a" }}
+  let post = batch --max 2 (fn(lines) {{ send c lines }})
+  fs.watch "{dir}/b.log" post
+}}
+sleep 300ms
+let append = sh"sh -c 'echo one >> {dir}/a.log; printf \"x\\ny\\n\" >> {dir}/b.log'"
+append.run
+let a = recv changed --timeout 5s
+let lines = recv c --timeout 5s
+t.cancel
+[a, lines]"#
+    ))
+    .unwrap();
+    std::fs::remove_dir_all(&tmp).unwrap();
+    let v = serde_json::to_value(&v).unwrap();
+    assert_eq!(v[0], "a");
+    // A batched callback gets the `[line, path, kind]` of every line.
+    let lines = v[1].as_array().unwrap();
+    assert_eq!(lines.len(), 2);
+    for (line, text) in lines.iter().zip(["x", "y"]) {
+        assert_eq!(line[0], text);
+        assert!(line[1].as_str().unwrap().ends_with("b.log"));
+        assert_eq!(line[2], "create");
+    }
+}
+
+#[test]
+fn test_watch_rotation() {
+    let tmp = std::env::temp_dir().join(format!("haksh-rotate-{}", std::process::id()));
+    std::fs::create_dir_all(&tmp).unwrap();
+    let dir = tmp.display();
+    let v = run(&format!(
+        r#"let c = chan
+let t = spawn {{
+  fs.watch "{dir}/app.log" (fn(line, path, kind) {{ send c [line, kind] }})
+}}
+sleep 300ms
+fn step(script) {{
+  let command = sh"sh -c"
+  command.run script
+  [recv c --timeout 5s, recv c --timeout 1500ms]
+}}
+let steps = [
+  step "echo one > {dir}/app.log",
+  step "echo two >> {dir}/app.log",
+  step "echo x > {dir}/app.log",
+  step "echo tail >> {dir}/app.log; mv {dir}/app.log {dir}/app.log.1; echo new > {dir}/app.log"
+]
+t.cancel
+steps"#
+    ))
+    .unwrap();
+    std::fs::remove_dir_all(&tmp).unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[[["one","create"],null],[["two","modify"],null],[["x","truncate"],null],[["tail","modify"],["new","rotate"]]]"#
+    );
+}
+
+#[test]
+fn test_watch_state() {
+    let tmp = std::env::temp_dir().join(format!("haksh-state-{}", std::process::id()));
+    std::fs::create_dir_all(&tmp).unwrap();
+    std::fs::write(tmp.join("app.log"), "old\n").unwrap();
+    let dir = tmp.display();
+    let v = run(&format!(
+        r#"let c = chan
+fn watcher() {{
+  spawn {{
+    fs.watch "{dir}/app.log" --state "{dir}/state.json" (fn(line, path, kind) {{ send c [line, kind] }})
+  }}
+}}
+fn shell(script) {{
+  let command = sh"sh -c"
+  command.run script
+}}
+fn drain() {{
+  let line = recv c --timeout 1500ms
+  if line == none then {{ [] }} else {{ [line] }}
+}}
+let first = watcher
+sleep 300ms
+shell "echo one >> {dir}/app.log"
+let a = recv c --timeout 5s
+let a2 = drain
+first.cancel
+try {{ join first }} catch e {{ }}
+shell "echo two >> {dir}/app.log; echo three >> {dir}/app.log"
+let second = watcher
+let b = recv c --timeout 5s
+let c2 = recv c --timeout 5s
+second.cancel
+try {{ join second }} catch e {{ }}
+shell "echo four >> {dir}/app.log; mv {dir}/app.log {dir}/app.log.1; echo five > {dir}/app.log"
+let third = watcher
+let d = recv c --timeout 5s
+let e = recv c --timeout 5s
+let e2 = drain
+third.cancel
+[a, a2, b, c2, d, e, e2]"#
+    ))
+    .unwrap();
+    std::fs::remove_dir_all(&tmp).unwrap();
+    assert_eq!(
+        serde_json::to_string(&v).unwrap(),
+        r#"[["one","modify"],[],["two","modify"],["three","modify"],["four","modify"],["five","rotate"],[]]"#
+    );
+}
@@ -1,4 +1,15 @@
 pub mod ast;
+pub mod combinator;
+pub mod cron;
+pub mod http;
 pub mod interpreter;
+pub mod module;
 pub mod parser;
-
+pub mod reactor;
+pub mod stream;
+pub mod tagged;
+pub mod task;
+pub mod time;
+pub mod timer;
+pub mod typeck;
+pub mod watch;
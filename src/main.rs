@@ -1,26 +1,114 @@
 use rustyline::error::ReadlineError;
 use rustyline::{DefaultEditor, Result};
 
-use haksh::interpreter::Environment;
+use haksh::ast::{Block, BlockElement};
+use haksh::interpreter::{run_interruptible, Environment, EvalResult, Value, STACK_SIZE};
 use haksh::parser::{parse_file, parse_line};
+use haksh::task::Task;
+use haksh::typeck;
 
-fn repl() -> Result<()> {
+/// Reads the lines after a `using` up to an empty line; they form its
+/// continuation. Ctrl-C or Ctrl-D gives up on the `using` instead.
+fn read_continuation(rl: &mut DefaultEditor) -> Result<Option<Vec<BlockElement>>> {
+    let mut elements = Vec::new();
+    loop {
+        let line = match rl.readline("...   >> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted | ReadlineError::Eof) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if line.trim().is_empty() {
+            return Ok(Some(elements));
+        }
+        match parse_line(&line) {
+            Ok((_, e)) => elements.push(e),
+            Err(e) => println!("Error: {e}"),
+        }
+    }
+}
+
+/// Runs work in the background as a task, so the prompt stays usable while
+/// e.g. a watch is running.
+fn spawn_job(id: usize, job: impl FnOnce() -> EvalResult + Send + 'static) -> Option<Task> {
+    println!("[job {id}] started");
+    let spawned = Task::spawn(move || {
+        let result = job();
+        match &result {
+            Ok(value) => println!("[job {id}] done: {value:?}"),
+            Err(e) => println!("[job {id}] failed: {e}"),
+        }
+        result
+    });
+    match spawned {
+        Ok(task) => Some(task),
+        Err(e) => {
+            println!("[job {id}] failed: {e}");
+            None
+        }
+    }
+}
+
+/// Handles `:jobs`, which lists the background jobs, and `:kill <id>`, which
+/// cancels one. Returns false if `line` is not such a command.
+fn job_command(line: &str, jobs: &[(usize, Task)]) -> bool {
+    let mut words = line.split_whitespace();
+    match (words.next(), words.next()) {
+        (Some(":jobs"), None) => {
+            for (id, task) in jobs {
+                let state = if task.is_done() { "done" } else { "running" };
+                println!("[job {id}] {state}");
+            }
+        }
+        (Some(":kill"), Some(id)) => match jobs.iter().find(|(i, _)| id == i.to_string()) {
+            Some((id, task)) => {
+                task.cancel();
+                println!("[job {id}] cancelling");
+            }
+            None => println!("Error: no job {id}"),
+        },
+        _ => return false,
+    }
+    true
+}
+
+fn repl(mut env: Environment) -> Result<()> {
     let mut rl = DefaultEditor::new()?;
+    let mut jobs = Vec::new();
+    // SAFETY: the handler only touches an atomic flag or exits.
+    unsafe { libc::signal(libc::SIGINT, on_repl_interrupt as libc::sighandler_t) };
 
-    let mut env = Environment::new();
     loop {
         let readline = rl.readline("haksh >> ");
         match readline {
+            Ok(line) if job_command(&line, &jobs) => {}
             Ok(line) => {
                 let result = parse_line(&line);
+                let id = jobs.len() + 1;
                 match result {
+                    Ok((_, using @ BlockElement::Using { .. })) => {
+                        let Some(rest) = read_continuation(&mut rl)? else {
+                            println!("'using' abandoned");
+                            continue;
+                        };
+                        let mut elements = vec![using];
+                        elements.extend(rest);
+                        // The job sees every binding made so far.
+                        let (block, env) = (Block(elements), env.clone());
+                        jobs.extend(spawn_job(id, move || block.run(&env)).map(|t| (id, t)));
+                    }
                     Ok(t) => {
                         println!("Parsed: {:?}", t);
 
-                        match t.1.evaluate_for_repl(&env) {
+                        // Ctrl-C cancels the line, but not the jobs.
+                        match run_interruptible(|| t.1.evaluate_for_repl(&env)) {
                             Ok((new_env, value)) => {
                                 env = new_env;
                                 println!("{:?}", value);
+                                // Callbacks the line registered run as a job.
+                                if let Some(reactor) = haksh::reactor::take() {
+                                    let job = move || reactor.run().map(|_| Value::Unit);
+                                    jobs.extend(spawn_job(id, job).map(|t| (id, t)));
+                                }
                             }
                             Err(e) => println!("Error: {e}"),
                         }
@@ -59,23 +147,66 @@ impl std::fmt::Display for InterpretError {
 }
 impl std::error::Error for InterpretError {}
 
-fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<_> = std::env::args().collect();
-    let file = args.get(1);
+/// The first Ctrl-C cancels the script so that its `defer` and `finally`
+/// blocks run; another one before that is noticed exits at once.
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if haksh::interpreter::cancel() {
+        // SAFETY: `_exit` is async-signal-safe.
+        unsafe { libc::_exit(130) };
+    }
+}
+
+/// In the REPL, Ctrl-C cancels the line being evaluated; another one before
+/// it has stopped exits at once.
+extern "C" fn on_repl_interrupt(_: libc::c_int) {
+    if haksh::interpreter::interrupt() {
+        // SAFETY: `_exit` is async-signal-safe.
+        unsafe { libc::_exit(130) };
+    }
+}
+
+type MainResult = std::result::Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+fn main() -> MainResult {
+    // Deeply recursive scripts need more native stack than the main thread gets.
+    let interpreter = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run)?;
+    interpreter
+        .join()
+        .map_err(|_| "interpreter thread panicked")?
+}
+
+fn run() -> MainResult {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let no_prelude = args.iter().any(|a| a == "--no-prelude");
+    let file = args.iter().find(|a| !a.starts_with("--"));
+
+    let env = Environment::new();
+    let env = if no_prelude { env } else { env.with_prelude()? };
 
     match file {
-        Some(file) => {
-            let file = std::fs::read_to_string(file).unwrap();
+        Some(path) => {
+            let file = std::fs::read_to_string(path).unwrap();
             let (_, file) = parse_file(&file).unwrap();
-            let env = Environment::new();
+            if let Err(errors) = typeck::check(&file) {
+                for e in &errors {
+                    eprintln!("{e}");
+                }
+                let msg = format!("{path}: {} type errors", errors.len());
+                return Err(Box::new(InterpretError { msg }));
+            }
+            let env = env.in_file(std::path::Path::new(path));
+            // SAFETY: the handler only touches an atomic flag or exits.
+            unsafe { libc::signal(libc::SIGINT, on_interrupt as libc::sighandler_t) };
             let v = file
-                .evaluate(&env)
-                .map_err(|msg| Box::new(InterpretError { msg }))?;
-            
+                .run(&env)
+                .map_err(|e| Box::new(InterpretError { msg: e.to_string() }))?;
+
             println!("{v:?}");
 
             Ok(())
         }
-        None => Ok(repl().map_err(Box::new)?),
+        None => Ok(repl(env).map_err(Box::new)?),
     }
 }
@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+use std::thread::ThreadId;
+
+use crate::interpreter::{Error, Value};
+
+thread_local! {
+    /// The modules this thread is in the middle of importing, outermost first.
+    /// Each thread has its own, so that tasks importing the same module at
+    /// once aren't mistaken for a cycle.
+    static LOADING: std::cell::RefCell<Vec<PathBuf>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Finds imported files and keeps the namespace each one evaluated to, so that
+/// a module is evaluated once per interpreter. A task importing a module that
+/// another task is evaluating waits for it.
+#[derive(Debug, Default)]
+pub struct ModuleLoader {
+    search_path: Vec<PathBuf>,
+    modules: Mutex<Modules>,
+    /// Notified whenever a module is done evaluating, successfully or not.
+    evaluated: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Modules {
+    loaded: BTreeMap<PathBuf, Value>,
+    /// The modules being evaluated, and by which thread.
+    loading: BTreeMap<PathBuf, ThreadId>,
+    /// The module each thread is waiting for another thread to evaluate.
+    waiting: HashMap<ThreadId, PathBuf>,
+}
+
+impl Modules {
+    /// If waiting for `path` would close a cycle of threads waiting for each
+    /// other, the modules along it, each importing the next.
+    fn cycle(&self, path: &Path, me: ThreadId) -> Option<Vec<PathBuf>> {
+        let mut cycle = vec![path.to_path_buf()];
+        let mut owner = self.loading.get(path)?;
+        while *owner != me {
+            let next = self.waiting.get(owner)?;
+            cycle.push(next.clone());
+            owner = self.loading.get(next)?;
+        }
+        Some(cycle)
+    }
+}
+
+fn cycle_error(cycle: &[PathBuf]) -> Error {
+    let cycle = cycle
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" -> ");
+    Error::new("import", format!("import cycle: {cycle}"))
+}
+
+impl ModuleLoader {
+    pub fn new(search_path: Vec<PathBuf>) -> ModuleLoader {
+        ModuleLoader {
+            search_path,
+            ..Default::default()
+        }
+    }
+
+    /// A loader searching the directories listed in `HAKSH_PATH`.
+    pub fn from_env() -> ModuleLoader {
+        let search_path = std::env::var_os("HAKSH_PATH")
+            .map(|paths| std::env::split_paths(&paths).collect())
+            .unwrap_or_default();
+        ModuleLoader::new(search_path)
+    }
+
+    /// Paths starting with `./` or `../` are relative to `dir`, the directory of
+    /// the importing file. Other relative paths are looked up in the search path.
+    pub fn resolve(&self, spec: &str, dir: Option<&Path>) -> Result<PathBuf, Error> {
+        let path = Path::new(spec);
+        let candidates = if path.is_absolute() {
+            vec![path.to_path_buf()]
+        } else if spec.starts_with("./") || spec.starts_with("../") {
+            vec![dir.unwrap_or(Path::new(".")).join(path)]
+        } else {
+            self.search_path.iter().map(|dir| dir.join(path)).collect()
+        };
+
+        candidates
+            .iter()
+            .flat_map(|c| [c.clone(), c.with_extension("haksh")])
+            .find(|c| c.is_file())
+            .ok_or(Error::new("import", format!("module {spec} not found")))?
+            .canonicalize()
+            .map_err(|e| Error::new("import", format!("{spec}: {e}")))
+    }
+
+    /// Returns the cached namespace for `path`, or evaluates it with `evaluate`.
+    pub fn load(
+        &self,
+        path: &Path,
+        evaluate: impl FnOnce(&Path) -> Result<Value, Error>,
+    ) -> Result<Value, Error> {
+        LOADING.with(|loading| {
+            let loading = loading.borrow();
+            match loading.iter().position(|p| p == path) {
+                Some(start) => Err(cycle_error(
+                    &[&loading[start..], &[path.to_path_buf()]].concat(),
+                )),
+                None => Ok(()),
+            }
+        })?;
+
+        let me = std::thread::current().id();
+        let mut modules = self.modules.lock().unwrap();
+        loop {
+            if let Some(module) = modules.loaded.get(path) {
+                return Ok(module.clone());
+            }
+            if !modules.loading.contains_key(path) {
+                break;
+            }
+            // Modules importing each other from different threads would wait forever.
+            if let Some(mut cycle) = modules.cycle(path, me) {
+                cycle.push(path.to_path_buf());
+                return Err(cycle_error(&cycle));
+            }
+            modules.waiting.insert(me, path.to_path_buf());
+            // Wake up now and then so a cancelled task doesn't wait for the module.
+            let timeout = std::time::Duration::from_millis(100);
+            modules = self.evaluated.wait_timeout(modules, timeout).unwrap().0;
+            modules.waiting.remove(&me);
+            crate::interpreter::check_cancelled()?;
+        }
+        modules.loading.insert(path.to_path_buf(), me);
+        drop(modules);
+
+        LOADING.with(|loading| loading.borrow_mut().push(path.to_path_buf()));
+        let module = evaluate(path);
+        LOADING.with(|loading| loading.borrow_mut().pop());
+
+        let mut modules = self.modules.lock().unwrap();
+        modules.loading.remove(path);
+        if let Ok(module) = &module {
+            modules.loaded.insert(path.to_path_buf(), module.clone());
+        }
+        drop(modules);
+        self.evaluated.notify_all();
+        module
+    }
+}
@@ -1,10 +1,10 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until},
-    character::complete::{char, line_ending, multispace0, space0, space1, u64},
-    combinator::{eof, map},
+    bytes::complete::{is_not, tag, take_until},
+    character::complete::{alpha1, char, multispace0, one_of, satisfy, space0, space1, u64},
+    combinator::{eof, map, map_opt, map_res, not, opt, value, verify},
     error::ParseError,
-    multi::{many1, separated_list0, separated_list1},
+    multi::{many0, separated_list0, separated_list1},
     sequence::{delimited, pair, preceded, terminated, tuple},
     IResult, Parser,
 };
@@ -20,27 +20,125 @@ pub fn parse_line(input: &str) -> IResult<&str, Line> {
 }
 
 fn expr(input: &str) -> IResult<&str, Expr> {
-    let pif = tuple((tag("if"),space0, expr,space0, tag("then"),space0, block, space0,tag("else"), space0,block));
+    let default = preceded(delimited(space0, tag("??"), space0), propagate);
+    map(pair(propagate, many0(default)), |(first, rest)| {
+        rest.into_iter().fold(first, |value, default| {
+            Expr::Coalesce(Coalesce {
+                value: Box::new(value),
+                default: Box::new(default),
+            })
+        })
+    })(input)
+}
+
+fn propagate(input: &str) -> IResult<&str, Expr> {
+    let propagate = opt(terminated(char('?'), not(one_of("?."))));
+    map(
+        pair(expr_inner, propagate),
+        |(e, propagate)| match propagate {
+            Some(_) => Expr::Propagate(Box::new(e)),
+            None => e,
+        },
+    )(input)
+}
+
+fn expr_inner(input: &str) -> IResult<&str, Expr> {
+    let pif = tuple((
+        tag("if"),
+        space0,
+        expr,
+        space0,
+        tag("then"),
+        space0,
+        block,
+        space0,
+        tag("else"),
+        space0,
+        block,
+    ));
+
+    let catch = map(
+        tuple((space0, tag("catch"), space1, identifer, space0, block)),
+        |(_, _catch, _, name, _, handler)| Catch {
+            name,
+            handler: Block(handler),
+        },
+    );
+    let finally = map(
+        tuple((space0, tag("finally"), space0, block)),
+        |(_, _finally, _, b)| Block(b),
+    );
+    let ptry = verify(
+        tuple((tag("try"), space0, block, opt(catch), opt(finally))),
+        |(_, _, _, catch, finally)| catch.is_some() || finally.is_some(),
+    );
 
     alt((
-        map(pif, |(_if, _,cond, _,_then,_, true_exp, _,_else,_, false_expr)| {
-            Expr::If(If {
-                cond: Box::new(cond),
-                true_exp: Box::new(Expr::Primary(PrimaryExpr::Block(Block(true_exp)))),
-                false_expr: Box::new(Expr::Primary(PrimaryExpr::Block(Block(false_expr)))),
+        map(ptry, |(_try, _, body, catch, finally)| {
+            Expr::Try(Try {
+                body: Block(body),
+                catch,
+                finally,
             })
         }),
+        map(
+            pif,
+            |(_if, _, cond, _, _then, _, true_exp, _, _else, _, false_expr)| {
+                Expr::If(If {
+                    cond: Box::new(cond),
+                    true_exp: Box::new(Expr::Primary(PrimaryExpr::Block(Block(true_exp)))),
+                    false_expr: Box::new(Expr::Primary(PrimaryExpr::Block(Block(false_expr)))),
+                })
+            },
+        ),
+        // A lone operand is left to the alternatives below, which also
+        // accept function applications.
+        verify(compare, |e| {
+            matches!(
+                e,
+                Expr::Compare(_) | Expr::AddSub(_) | Expr::MulDiv(_) | Expr::Range(_)
+            )
+        }),
+        map(function_literal, Expr::Primary),
         map(function_application, Expr::FunctionApplication),
         map(primary_expr, Expr::Primary),
     ))(input)
 }
 
-fn add_sub(input: &str) -> IResult<&str, BinOp<AddSubOp>> {
+fn compare(input: &str) -> IResult<&str, Expr> {
+    let op = alt((
+        map(tag("=="), |_| CompareOp::Eq),
+        map(tag("!="), |_| CompareOp::Ne),
+        map(tag("<="), |_| CompareOp::Le),
+        map(tag(">="), |_| CompareOp::Ge),
+        map(char('<'), |_| CompareOp::Lt),
+        map(char('>'), |_| CompareOp::Gt),
+    ));
+    binop(range, op)(input)
+}
+
+/// `start..end` or `start..=end`, optionally followed by `--step n`. A lone
+/// operand is returned unchanged.
+fn range(input: &str) -> IResult<&str, Expr> {
+    let op = alt((value(true, tag("..=")), value(false, tag(".."))));
+    let step = preceded(tuple((space0, tag("--step"), space1)), primary_expr);
+    let tail = tuple((op, space0, add_sub, opt(step)));
+    map(pair(add_sub, opt(tail)), |(start, tail)| match tail {
+        Some((inclusive, _, end, step)) => Expr::Range(Range {
+            start: Box::new(start),
+            end: Box::new(end),
+            inclusive,
+            step,
+        }),
+        None => start,
+    })(input)
+}
+
+fn add_sub(input: &str) -> IResult<&str, Expr> {
     let add = map(char('+'), |_| AddSubOp::Add);
     let sub = map(char('-'), |_| AddSubOp::Sub);
     let op = alt((add, sub));
-    let term = |input| alt((map(mul_div, Expr::MulDiv), map(primary_expr, Expr::Primary)))(input);
-    binop(term, op)(input)
+    binop(mul_div, op)(input)
 }
 
 fn space<'t, O, E: ParseError<&'t str>>(
@@ -49,10 +147,12 @@ fn space<'t, O, E: ParseError<&'t str>>(
     move |input| delimited(space0, p, space0)(input)
 }
 
+/// Parses `term (op term)*` as a left-associative chain. Each term is parsed
+/// once, and a lone term is returned unchanged.
 fn binop<'t, Op: crate::interpreter::BinaryOperator, E: ParseError<&'t str>, G>(
     term: fn(&'t str) -> IResult<&'t str, Expr, E>,
     op: G,
-) -> impl FnOnce(&'t str) -> IResult<&'t str, BinOp<Op>, E>
+) -> impl FnOnce(&'t str) -> IResult<&'t str, Expr, E>
 where
     G: Parser<&'t str, Op, E>,
 {
@@ -61,30 +161,22 @@ where
         map(
             pair(
                 terminated(term, space0),
-                many1(pair(op, terminated(term, space0))),
+                many0(pair(op, terminated(term, space0))),
             ),
             |(first, rest)| {
-                let mut rest: std::collections::VecDeque<_> = rest.into_iter().collect();
-                let (op1, right1) = rest.pop_front().unwrap();
-
-                rest.into_iter().fold(
-                    BinOp {
-                        left: Box::new(first),
-                        op: op1,
-                        right: Box::new(right1),
-                    },
-                    |acc, (op, e)| BinOp {
-                        left: Box::new(Op::into_expr()(acc)),
+                rest.into_iter().fold(first, |acc, (op, e)| {
+                    Op::into_expr()(BinOp {
+                        left: Box::new(acc),
                         op,
                         right: Box::new(e),
-                    },
-                )
+                    })
+                })
             },
         )(input)
     }
 }
 
-fn mul_div(input: &str) -> IResult<&str, BinOp<MulDivOp>> {
+fn mul_div(input: &str) -> IResult<&str, Expr> {
     let term = |input| map(primary_expr, Expr::Primary)(input);
 
     let add = map(char('*'), |_| MulDivOp::Mul);
@@ -97,30 +189,41 @@ fn function_name(input: &str) -> IResult<&str, Vec<String>> {
     separated_list1(char('.'), identifer)(input)
 }
 
+/// Like `function_name`, but also accepts `?.` and records where it was used.
+fn function_path(input: &str) -> IResult<&str, Vec<(bool, String)>> {
+    let separator = alt((map(tag("?."), |_| true), map(char('.'), |_| false)));
+    map(
+        pair(identifer, many0(pair(separator, identifer))),
+        |(first, rest)| std::iter::once((false, first)).chain(rest).collect(),
+    )(input)
+}
+
 fn function_application(input: &str) -> IResult<&str, FunctionApplication> {
     enum Type {
         Option(String, PrimaryExpr),
         Arg(PrimaryExpr),
     }
-    let identifier = map(space(function_name), |i| {
+    let identifier = map(space(function_path), |i| {
         let mut i = i.iter().rev();
-        let a = i.next().unwrap();
+        let (safe, a) = i.next().unwrap();
         i.fold(
             Identifier {
                 child: None,
                 path: a.to_string(),
+                safe: *safe,
             },
-            |acc, path| Identifier {
+            |acc, (safe, path)| Identifier {
                 child: Some(Box::new(acc)),
                 path: path.to_string(),
+                safe: *safe,
             },
         )
     });
 
-    // let option = preceded(tag("--"), pair(space(identifer), space(primary_expr)));
-    // let option = map(option, |(k, v)| Type::Option(k, v));
+    let option = preceded(tag("--"), tuple((identifer, space1, primary_expr)));
+    let option = map(option, |(k, _, v)| Type::Option(k, v));
     let arg = map(primary_expr, Type::Arg);
-    let opargs = separated_list0(space1, arg);
+    let opargs = separated_list0(space1, alt((option, arg)));
 
     let r = tuple((identifier, opargs));
     map(r, |(fident, opargs)| {
@@ -174,37 +277,213 @@ fn pstring(input: &str) -> IResult<&str, String> {
     )(input)
 }
 
-fn pcompound(input: &str) -> IResult<&str, std::collections::BTreeMap<String, Expr>> {
-    let p = delimited(
-        char('('),
-        separated_list0(char(','), tuple((identifer, char('='), expr))),
-        char(')'),
+/// `250ms`, `5s`, `10m`, `2h` or `1d`.
+fn duration(input: &str) -> IResult<&str, crate::time::Duration> {
+    let unit = alt((tag("ms"), tag("s"), tag("m"), tag("h"), tag("d")));
+    let p = terminated(
+        pair(u64, unit),
+        not(satisfy(|c: char| c.is_alphanumeric() || c == '_')),
+    );
+    map_opt(p, |(n, unit)| crate::time::Duration::from_unit(n, unit))(input)
+}
+
+/// `tag"..."`, handed to the handler registered for `tag` while parsing.
+fn tagged_string(input: &str) -> IResult<&str, TaggedString> {
+    map_res(pair(alpha1, tagged_content), |(tag, content)| {
+        crate::tagged::parse(tag, &content)
+    })(input)
+}
+
+/// The body of a tagged literal. Backslashes are left for the handler to
+/// interpret, except that `\"` stands for a quote.
+fn tagged_content(input: &str) -> IResult<&str, String> {
+    let piece = alt((
+        value("\"", tag("\\\"")),
+        value("\\", char('\\')),
+        is_not("\\\""),
+    ));
+    delimited(char('"'), map(many0(piece), |p| p.concat()), char('"'))(input)
+}
+
+fn pcompound(input: &str) -> IResult<&str, Vec<CompoundEntry>> {
+    let spread = map(preceded(tag(".."), expr), CompoundEntry::Spread);
+    let field = map(
+        tuple((function_name, space0, char('='), space0, expr)),
+        |(path, _, _eq, _, def)| CompoundEntry::Field { path, def },
     );
+    let entry = delimited(space0, alt((spread, field)), space0);
+    delimited(char('('), separated_list0(char(','), entry), char(')'))(input)
+}
+
+fn plist(input: &str) -> IResult<&str, Vec<Expr>> {
+    delimited(
+        char('['),
+        separated_list0(char(','), delimited(multispace0, expr, multispace0)),
+        char(']'),
+    )(input)
+}
+
+/// `(expr)` is sugar for a block holding a single expression.
+fn paren(input: &str) -> IResult<&str, PrimaryExpr> {
+    let p = delimited(char('('), delimited(space0, expr, space0), char(')'));
     map(p, |e| {
-        let mut map = std::collections::BTreeMap::new();
-        e.iter().for_each(|(key, _, def)| {
-            map.insert(key.clone(), def.clone());
-        });
+        PrimaryExpr::Block(Block(vec![BlockElement::Expr(e)]))
+    })(input)
+}
+
+fn pattern(input: &str) -> IResult<&str, Pattern> {
+    let annotation = preceded(tuple((space0, char(':'), space0)), type_expr);
+    map(
+        pair(bare_pattern, opt(annotation)),
+        |(pattern, ty)| match ty {
+            Some(ty) => Pattern::Annotated {
+                pattern: Box::new(pattern),
+                ty,
+            },
+            None => pattern,
+        },
+    )(input)
+}
+
+fn bare_pattern(input: &str) -> IResult<&str, Pattern> {
+    let field = map(
+        pair(space(identifer), opt(preceded(char('='), space(pattern)))),
+        |(key, pattern)| {
+            let pattern = pattern.unwrap_or_else(|| Pattern::Name(key.clone()));
+            (key, pattern)
+        },
+    );
+    let compound = map(
+        delimited(char('('), separated_list0(char(','), field), char(')')),
+        |fields| Pattern::Compound(fields.into_iter().collect()),
+    );
 
-        map
+    enum Item {
+        Pattern(Pattern),
+        Rest(String),
+    }
+    let item = alt((
+        map(space(rest_pattern), Item::Rest),
+        map(space(pattern), Item::Pattern),
+    ));
+    // A rest can only come last, so a list has at most one.
+    let list = map_opt(
+        delimited(char('['), separated_list0(char(','), item), char(']')),
+        |items| {
+            let mut patterns = Vec::new();
+            let mut rest = None;
+            for item in items {
+                match item {
+                    _ if rest.is_some() => return None,
+                    Item::Pattern(p) => patterns.push(p),
+                    Item::Rest(name) => rest = Some(name),
+                }
+            }
+            Some(Pattern::List {
+                items: patterns,
+                rest,
+            })
+        },
+    );
+
+    alt((compound, list, map(identifer, Pattern::Name)))(input)
+}
+
+/// `Int`, `[String]`, `(content: String, retries: Int?)`, with an optional trailing `?`.
+fn type_expr(input: &str) -> IResult<&str, TypeExpr> {
+    let field = map(
+        tuple((space(identifer), char(':'), space(type_expr))),
+        |(name, _, t)| (name, t),
+    );
+    let compound = map(
+        delimited(char('('), separated_list0(char(','), field), char(')')),
+        TypeExpr::Compound,
+    );
+    let list = map(delimited(char('['), space(type_expr), char(']')), |t| {
+        TypeExpr::List(Box::new(t))
+    });
+    let base = alt((compound, list, map(identifer, TypeExpr::Named)));
+    map(pair(base, opt(char('?'))), |(t, optional)| match optional {
+        Some(_) => TypeExpr::Optional(Box::new(t)),
+        None => t,
+    })(input)
+}
+
+fn rest_pattern(input: &str) -> IResult<&str, String> {
+    preceded(tag(".."), identifer)(input)
+}
+
+fn params(input: &str) -> IResult<&str, Vec<Pattern>> {
+    delimited(
+        char('('),
+        separated_list0(char(','), space(pattern)),
+        char(')'),
+    )(input)
+}
+
+/// `fn(x, y) { ... }` evaluates to a closure, in the same shape `using` builds.
+fn function_literal(input: &str) -> IResult<&str, PrimaryExpr> {
+    let p = tuple((tag("fn"), space0, params, space0, block));
+    map(p, |(_fn, _, params, _, body)| {
+        PrimaryExpr::Block(Block(vec![BlockElement::AnonymousFunction(
+            AnonymousFunction {
+                params,
+                body: Block(body),
+            },
+        )]))
     })(input)
 }
 
 pub fn primary_expr(input: &str) -> IResult<&str, PrimaryExpr> {
-    let pb = map(pbool, |b| PrimaryExpr::Bool(b));
+    let pb = map(pbool, PrimaryExpr::Bool);
     let block = map(block, |b| PrimaryExpr::Block(Block(b)));
-    let u = map(u64, |u| PrimaryExpr::DecimalInt(u));
-    let id = map(identifer, |id| PrimaryExpr::Identifier(id));
+    let u = map(u64, PrimaryExpr::DecimalInt);
+    let d = map(duration, PrimaryExpr::Duration);
+    let id = map(identifer, PrimaryExpr::Identifier);
     let ps = map(pstring, PrimaryExpr::StringLiteral);
+    let none = map(
+        terminated(
+            tag("none"),
+            not(satisfy(|c: char| c.is_alphanumeric() || c == '_')),
+        ),
+        |_| PrimaryExpr::None,
+    );
     let pc = map(pcompound, PrimaryExpr::Compound);
-    alt((pb, block, u, id, ps, pc))(input)
+    let pl = map(plist, PrimaryExpr::List);
+    let ts = map(tagged_string, PrimaryExpr::TaggedString);
+    alt((
+        pb,
+        none,
+        block,
+        d,
+        u,
+        function_literal,
+        ts,
+        id,
+        ps,
+        pc,
+        paren,
+        pl,
+    ))(input)
 }
 
-fn identifer(input: &str) -> IResult<&str, String> {
-    let re = regex::Regex::new(r"^\p{XID_Start}\p{XID_Continue}*").unwrap();
-    let ident = re_find(re);
+const KEYWORDS: &[&str] = &[
+    "if", "then", "else", "let", "mut", "using", "fn", "true", "false", "try", "catch", "import",
+    "export", "as", "none", "defer", "finally",
+];
 
-    ident(input).map(|(s, i)| (s, i.to_string()))
+fn identifer(input: &str) -> IResult<&str, String> {
+    static RE: std::sync::OnceLock<regex::Regex> = std::sync::OnceLock::new();
+    let re = RE.get_or_init(|| regex::Regex::new(r"^\p{XID_Start}\p{XID_Continue}*").unwrap());
+    let ident = re_find(re.clone());
+
+    match ident(input) {
+        // A name directly followed by a string is a tagged literal such as `re"..."`.
+        Ok((rest, i)) if KEYWORDS.contains(&i) || rest.starts_with('"') => Err(nom::Err::Error(
+            nom::error::Error::new(input, nom::error::ErrorKind::Verify),
+        )),
+        r => r.map(|(s, i)| (s, i.to_string())),
+    }
 }
 
 #[test]
@@ -226,6 +505,26 @@ fs.cwd"#
     assert_eq!(i, ";hoge");
 }
 
+#[test]
+fn test_none_prefix() {
+    let (i, e) = primary_expr("nonexistent + 1").unwrap();
+    assert_eq!(i, " + 1");
+    assert!(matches!(e, PrimaryExpr::Identifier(name) if name == "nonexistent"));
+    let (i, e) = primary_expr("none ?? 1").unwrap();
+    assert_eq!(i, " ?? 1");
+    assert!(matches!(e, PrimaryExpr::None));
+    assert!(parse_file("let nonexistent = 1\nid nonexistent").is_ok());
+}
+
+#[test]
+fn test_rest_pattern() {
+    let (_, p) = pattern("[a, ..r]").unwrap();
+    assert!(matches!(p, Pattern::List { items, rest: Some(r) } if items.len() == 1 && r == "r"));
+    assert!(pattern("[..a, b]").is_err());
+    assert!(pattern("[a, ..r, ..s]").is_err());
+    assert!(parse_file("let [..a, b] = [1, 2, 3]").is_err());
+}
+
 fn block_element_using(input: &str) -> IResult<&str, BlockElement> {
     let a = tuple((
         tag("using"),
@@ -242,35 +541,97 @@ fn block_element_using(input: &str) -> IResult<&str, BlockElement> {
     })(input)
 }
 
-fn block_element(input: &str) -> IResult<&str, BlockElement> {
-    let block_element_var = map(
-        tuple((
-            tag("let"),
-            space0,
-            identifer,
-            space0,
-            tag("="),
-            space0,
-            expr,
-        )),
-        |(_let, _, ident, _, _eq, _, def)| BlockElement::Var {
-            name: ident.to_string(),
+fn block_element_var(input: &str) -> IResult<&str, BlockElement> {
+    let a = tuple((
+        tag("let"),
+        space0,
+        opt(terminated(tag("mut"), space1)),
+        pattern,
+        space0,
+        tag("="),
+        space0,
+        expr,
+    ));
+    map(a, |(_let, _, mutable, pattern, _, _eq, _, def)| {
+        BlockElement::Var {
+            pattern,
             def,
+            mutable: mutable.is_some(),
+        }
+    })(input)
+}
+
+fn block_element_fn(input: &str) -> IResult<&str, BlockElement> {
+    let a = tuple((tag("fn"), space1, identifer, space0, params, space0, block));
+    map(a, |(_fn, _, name, _, params, _, body)| BlockElement::Fn {
+        name,
+        function: AnonymousFunction {
+            params,
+            body: Block(body),
         },
+    })(input)
+}
+
+fn block_element_import(input: &str) -> IResult<&str, BlockElement> {
+    let a = tuple((
+        tag("import"),
+        space1,
+        pstring,
+        space1,
+        tag("as"),
+        space1,
+        identifer,
+    ));
+    map(a, |(_import, _, path, _, _as, _, name)| {
+        BlockElement::Import { path, name }
+    })(input)
+}
+
+fn block_element_defer(input: &str) -> IResult<&str, BlockElement> {
+    let a = preceded(pair(tag("defer"), space0), block);
+    map(a, |b| BlockElement::Defer(Block(b)))(input)
+}
+
+fn block_element_export(input: &str) -> IResult<&str, BlockElement> {
+    let a = preceded(
+        pair(tag("export"), space1),
+        alt((block_element_var, block_element_fn)),
+    );
+    map(a, |e| BlockElement::Export(Box::new(e)))(input)
+}
+
+fn block_element(input: &str) -> IResult<&str, BlockElement> {
+    let block_element_assign = map(
+        tuple((identifer, space0, char('='), not(char('=')), space0, expr)),
+        |(name, _, _eq, _, _, def)| BlockElement::Assign { name, def },
     );
     let expr = map(expr, BlockElement::Expr);
-    let mut block_element = alt((block_element_var, block_element_using, expr));
+    let mut block_element = alt((
+        block_element_var,
+        block_element_fn,
+        block_element_using,
+        block_element_import,
+        block_element_export,
+        block_element_defer,
+        block_element_assign,
+        expr,
+    ));
 
     block_element(input)
 }
 
 fn block_inner(input: &str) -> IResult<&str, Vec<BlockElement>> {
-    separated_list0(alt((char(';'), char('\n'))), block_element)(input)
+    let separator = delimited(space0, alt((char(';'), char('\n'))), multispace0);
+    delimited(
+        multispace0,
+        separated_list0(separator, block_element),
+        multispace0,
+    )(input)
 }
 
 pub fn parse_file(input: &str) -> IResult<&str, Block> {
-    let a = terminated(block_inner, pair(multispace0, eof));
-    map(a, |v| Block(v))(input)
+    let a = terminated(block_inner, eof);
+    map(a, Block)(input)
 }
 
 fn block(input: &str) -> IResult<&str, Vec<BlockElement>> {
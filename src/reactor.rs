@@ -0,0 +1,284 @@
+use crate::interpreter::{Error, EvalResult, Value};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Duration;
+
+/// How long the event loop waits for an event before checking for cancellation.
+const POLL: Duration = Duration::from_millis(100);
+
+/// Something an event source needs to keep alive while it is registered, such
+/// as a file watcher. Dropping it must stop the source.
+pub type Guard = Box<dyn Send>;
+
+enum Message {
+    Event {
+        source: usize,
+        arguments: Vec<Value>,
+        reply: Option<mpsc::Sender<EvalResult>>,
+    },
+    Done(usize),
+    Failed(usize, Error),
+}
+
+/// Hands the events of one source to the event loop. Dropping it tells the
+/// loop the source is finished.
+pub struct Emitter {
+    source: usize,
+    tx: mpsc::Sender<Message>,
+}
+
+impl Emitter {
+    /// Queues `value` for the source's callback. Returns false once the event
+    /// loop has shut down, so the source can stop too.
+    pub fn emit(&self, value: Value) -> bool {
+        self.send(vec![value], None)
+    }
+
+    /// Like `emit`, for callbacks that take several arguments.
+    pub fn call(&self, arguments: Vec<Value>) -> bool {
+        self.send(arguments, None)
+    }
+
+    /// Queues a call and waits for the callback's result.
+    pub fn request(&self, arguments: Vec<Value>) -> EvalResult {
+        let (reply, result) = mpsc::channel();
+        if !self.send(arguments, Some(reply)) {
+            return Err(Error::new("cancelled", "event loop shut down"));
+        }
+        result
+            .recv()
+            .unwrap_or(Err(Error::new("cancelled", "event loop shut down")))
+    }
+
+    /// Stops the source, making the event loop fail with `error`.
+    pub fn fail(&self, error: Error) {
+        let _ = self.tx.send(Message::Failed(self.source, error));
+    }
+
+    fn send(&self, arguments: Vec<Value>, reply: Option<mpsc::Sender<EvalResult>>) -> bool {
+        let event = Message::Event {
+            source: self.source,
+            arguments,
+            reply,
+        };
+        self.tx.send(event).is_ok()
+    }
+}
+
+impl Drop for Emitter {
+    fn drop(&mut self) {
+        let _ = self.tx.send(Message::Done(self.source));
+    }
+}
+
+/// Something to do once the event loop stops, such as a last call to a callback.
+type Cleanup = Box<dyn FnOnce() -> Result<(), Error> + Send>;
+
+/// The event loop of one interpreter thread. Builtins like `fs.watch`
+/// register event sources with it, and once the script has run, every event
+/// is passed to its source's callback in the order it arrived.
+pub struct Reactor {
+    tx: mpsc::Sender<Message>,
+    rx: mpsc::Receiver<Message>,
+    sources: BTreeMap<usize, (Value, Guard)>,
+    next_id: usize,
+    /// What to do when the loop stops, such as making a pending debounced
+    /// call, with the key it was registered under if any, oldest first.
+    cleanups: Vec<(Option<usize>, Cleanup)>,
+}
+
+impl Default for Reactor {
+    fn default() -> Reactor {
+        let (tx, rx) = mpsc::channel();
+        Reactor {
+            tx,
+            rx,
+            sources: BTreeMap::new(),
+            next_id: 0,
+            cleanups: Vec::new(),
+        }
+    }
+}
+
+struct Stop(Arc<AtomicBool>);
+
+impl Drop for Stop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A guard for sources that run on their own thread: the flag is set once
+/// the guard is dropped, and the thread should then stop.
+pub fn stop_flag() -> (Guard, Arc<AtomicBool>) {
+    let stopped = Arc::new(AtomicBool::new(false));
+    (Box::new(Stop(stopped.clone())), stopped)
+}
+
+thread_local! {
+    static CURRENT: std::cell::RefCell<Reactor> = std::cell::RefCell::new(Reactor::default());
+    /// How many sources and cleanups this thread has registered so far.
+    static REGISTERED: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+/// A count that changes whenever something is registered with this thread's
+/// event loop, so code can tell whether it registered anything meanwhile.
+pub fn registered() -> u64 {
+    REGISTERED.with(|registered| registered.get())
+}
+
+fn count_registration() {
+    REGISTERED.with(|registered| registered.set(registered.get() + 1));
+}
+
+/// Registers a source whose events are passed to `callback`. `start` gets
+/// the source's emitter and returns what keeps the source running.
+pub fn register(
+    callback: Value,
+    start: impl FnOnce(Emitter) -> Result<Guard, Error>,
+) -> Result<(), Error> {
+    let emitter = CURRENT.with(|reactor| {
+        let mut reactor = reactor.borrow_mut();
+        reactor.next_id += 1;
+        Emitter {
+            source: reactor.next_id,
+            tx: reactor.tx.clone(),
+        }
+    });
+    let source = emitter.source;
+    let guard = start(emitter)?;
+    count_registration();
+    CURRENT.with(|reactor| {
+        reactor
+            .borrow_mut()
+            .sources
+            .insert(source, (callback, guard))
+    });
+    Ok(())
+}
+
+/// Has `callback` called with what `flush` returns once the event loop
+/// stops, however it stops. Registering again under the same `key` replaces
+/// the earlier flush. Unlike a source, this doesn't keep the loop running.
+pub fn on_stop(
+    key: usize,
+    callback: Value,
+    flush: impl FnOnce() -> Option<Vec<Value>> + Send + 'static,
+) {
+    let cleanup: Cleanup = Box::new(move || match flush() {
+        Some(arguments) => callback.try_evaluate_as_fn(arguments).map(|_| ()),
+        None => Ok(()),
+    });
+    CURRENT.with(|reactor| {
+        let cleanups = &mut reactor.borrow_mut().cleanups;
+        match cleanups.iter_mut().find(|(k, _)| *k == Some(key)) {
+            Some(entry) => entry.1 = cleanup,
+            None => cleanups.push((Some(key), cleanup)),
+        }
+    });
+    count_registration();
+}
+
+/// Runs `cleanup` once the event loop stops, after the cleanups registered
+/// before it. This is how `defer` waits for the callbacks of its block.
+pub fn defer(cleanup: impl FnOnce() -> Result<(), Error> + Send + 'static) {
+    CURRENT.with(|reactor| {
+        reactor
+            .borrow_mut()
+            .cleanups
+            .push((None, Box::new(cleanup)))
+    });
+    count_registration();
+}
+
+/// Takes this thread's event loop if anything is registered with it, so it
+/// can be run elsewhere.
+pub fn take() -> Option<Reactor> {
+    CURRENT.with(|reactor| {
+        let mut reactor = reactor.borrow_mut();
+        let empty = reactor.sources.is_empty() && reactor.cleanups.is_empty();
+        (!empty).then(|| std::mem::take(&mut *reactor))
+    })
+}
+
+/// Runs this thread's event loop until no sources are left.
+pub fn run() -> Result<(), Error> {
+    match take() {
+        Some(reactor) => reactor.run(),
+        None => Ok(()),
+    }
+}
+
+impl Reactor {
+    /// Dispatches events until every source is finished, a callback fails or
+    /// the script is cancelled. Sources still registered then are stopped.
+    pub fn run(self) -> Result<(), Error> {
+        CURRENT.with(|reactor| *reactor.borrow_mut() = self);
+        let result = dispatch();
+        let reactor = CURRENT.with(|reactor| std::mem::take(&mut *reactor.borrow_mut()));
+        // Dropping the guards stops whatever is still running.
+        drop(reactor.sources);
+        // Cleanups run even if the script was cancelled, and an error from
+        // one doesn't keep the others from running.
+        let cleaned_up = crate::interpreter::cleaning_up(|| {
+            let mut result = Ok(());
+            for (_, cleanup) in reactor.cleanups {
+                if let Err(e) = cleanup() {
+                    result = result.and(Err(e));
+                }
+            }
+            result
+        });
+        result.and(cleaned_up)
+    }
+}
+
+fn dispatch() -> Result<(), Error> {
+    loop {
+        // Checked on every turn, as a busy source might never let it time out.
+        crate::interpreter::check_cancelled()?;
+        let received = CURRENT.with(|reactor| {
+            let reactor = reactor.borrow();
+            if reactor.sources.is_empty() {
+                return None;
+            }
+            Some(reactor.rx.recv_timeout(POLL))
+        });
+        let message = match received {
+            None => return Ok(()),
+            Some(Ok(message)) => message,
+            Some(Err(_)) => continue,
+        };
+        match message {
+            Message::Event {
+                source,
+                arguments,
+                reply,
+            } => {
+                let callback = CURRENT.with(|reactor| {
+                    let reactor = reactor.borrow();
+                    reactor.sources.get(&source).map(|(f, _)| f.clone())
+                });
+                // Events still queued from a stopped source are dropped.
+                let Some(callback) = callback else { continue };
+                let result = callback.try_evaluate_as_fn(arguments);
+                match reply {
+                    Some(reply) => {
+                        let _ = reply.send(result.clone());
+                    }
+                    None => {
+                        result?;
+                    }
+                }
+            }
+            Message::Done(source) => {
+                CURRENT.with(|reactor| reactor.borrow_mut().sources.remove(&source));
+            }
+            Message::Failed(source, error) => {
+                CURRENT.with(|reactor| reactor.borrow_mut().sources.remove(&source));
+                return Err(error);
+            }
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use crate::interpreter::{Error, EvalResult, Value};
+use std::io::BufRead;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// A sequence of values produced on demand, such as lines appended to a
+/// watched file. Reading consumes it, so every copy shares one position.
+#[derive(Clone)]
+pub struct Stream(Arc<Source>);
+
+/// Where a stream's items come from. Stages that call back into the script
+/// hold no lock while they do, so a callback may read the stream it belongs to.
+enum Source {
+    Items(Mutex<Box<dyn Iterator<Item = EvalResult> + Send>>),
+    Map(Stream, Value),
+    Filter(Stream, Value),
+    Take(Stream, AtomicU64),
+    Chunk(Stream, u64),
+    Dedupe(Stream, Mutex<Option<Value>>),
+}
+
+impl std::fmt::Debug for Stream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Stream")
+    }
+}
+
+impl Iterator for Stream {
+    type Item = EvalResult;
+
+    fn next(&mut self) -> Option<EvalResult> {
+        match &*self.0 {
+            Source::Items(items) => items.lock().unwrap().next(),
+            Source::Map(source, f) => Some(
+                source
+                    .clone()
+                    .next()?
+                    .and_then(|v| f.try_evaluate_as_fn(vec![v])),
+            ),
+            Source::Filter(source, f) => loop {
+                let v = match source.clone().next()? {
+                    Ok(v) => v,
+                    Err(e) => return Some(Err(e)),
+                };
+                match f.try_evaluate_as_fn(vec![v.clone()]) {
+                    Ok(Value::Bool(true)) => return Some(Ok(v)),
+                    Ok(Value::Bool(false)) => continue,
+                    Ok(v) => {
+                        return Some(Err(
+                            format!("filter callback returned {v:?}, not bool").into()
+                        ))
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+            },
+            Source::Take(source, left) => {
+                left.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .ok()?;
+                source.clone().next()
+            }
+            Source::Chunk(source, size) => {
+                let mut chunk = Vec::new();
+                while (chunk.len() as u64) < *size {
+                    match source.clone().next() {
+                        Some(Ok(v)) => chunk.push(v),
+                        Some(Err(e)) => return Some(Err(e)),
+                        None => break,
+                    }
+                }
+                (!chunk.is_empty()).then_some(Ok(Value::List(chunk)))
+            }
+            Source::Dedupe(source, last) => loop {
+                match source.clone().next()? {
+                    Ok(v) => {
+                        let mut last = last.lock().unwrap();
+                        if last.as_ref().is_some_and(|last| last.same(&v)) {
+                            continue;
+                        }
+                        *last = Some(v.clone());
+                        return Some(Ok(v));
+                    }
+                    Err(e) => return Some(Err(e)),
+                }
+            },
+        }
+    }
+}
+
+impl Stream {
+    pub fn new(items: impl Iterator<Item = EvalResult> + Send + 'static) -> Stream {
+        Stream(Arc::new(Source::Items(Mutex::new(Box::new(items)))))
+    }
+
+    pub fn from_values(values: impl Iterator<Item = Value> + Send + 'static) -> Stream {
+        Stream::new(values.map(Ok))
+    }
+
+    pub fn map(self, f: Value) -> Stream {
+        Stream(Arc::new(Source::Map(self, f)))
+    }
+
+    pub fn filter(self, f: Value) -> Stream {
+        Stream(Arc::new(Source::Filter(self, f)))
+    }
+
+    pub fn take(self, n: u64) -> Stream {
+        Stream(Arc::new(Source::Take(self, AtomicU64::new(n))))
+    }
+
+    /// Groups items into lists of `size`; the last one may be shorter.
+    pub fn chunk(self, size: u64) -> Stream {
+        Stream(Arc::new(Source::Chunk(self, size)))
+    }
+
+    /// Drops items equal to the one right before them.
+    pub fn dedupe(self) -> Stream {
+        Stream(Arc::new(Source::Dedupe(self, Mutex::new(None))))
+    }
+
+    /// Calls `f` with every item; an error from either stops the stream.
+    pub fn for_each(self, f: &Value) -> EvalResult {
+        for v in self {
+            f.try_evaluate_as_fn(vec![v?])?;
+        }
+        Ok(Value::Unit)
+    }
+}
+
+/// Lines appended to the watched files from now on.
+pub fn watch_lines(targets: Vec<crate::watch::Target>) -> Result<Stream, Error> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let watcher = crate::watch::watch(targets, None, move |line| {
+        tx.send(line.map(|line| Value::String(line.text))).is_ok()
+    })?;
+    Ok(Stream::new(std::iter::from_fn(move || {
+        // Dropping the watcher would stop the watch.
+        let _watching = &watcher;
+        loop {
+            // Wake up now and then so a cancelled script doesn't wait for the file.
+            match rx.recv_timeout(std::time::Duration::from_millis(100)) {
+                Ok(line) => return Some(line),
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                    if let Err(e) = crate::interpreter::check_cancelled() {
+                        return Some(Err(e));
+                    }
+                }
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    })))
+}
+
+/// The lines a child process writes to stdout. A failing exit status
+/// surfaces as an error once the output is exhausted.
+pub fn process_lines(mut command: std::process::Command) -> Result<Stream, Error> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let mut child = command
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| Error::new("io", format!("{program}: {e}")))?;
+    let mut lines = std::io::BufReader::new(child.stdout.take().expect("stdout is piped")).lines();
+    let mut done = false;
+    Ok(Stream::new(std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        match lines.next() {
+            Some(Ok(line)) => Some(Ok(Value::String(line))),
+            Some(Err(e)) => Some(Err(Error::new("io", e.to_string()))),
+            None => {
+                done = true;
+                match child.wait() {
+                    Ok(status) if status.success() => None,
+                    Ok(status) => Some(Err(Error::new("io", format!("{program}: {status}")))),
+                    Err(e) => Some(Err(Error::new("io", e.to_string()))),
+                }
+            }
+        }
+    })))
+}
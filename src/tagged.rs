@@ -0,0 +1,179 @@
+use crate::ast::TaggedString;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock, RwLock};
+
+/// Builds the value of a `tag"..."` literal, or explains why the literal is
+/// invalid. Handlers run while the script is parsed, so a bad literal fails
+/// at load instead of when it's used. Handlers of new tags usually give a
+/// `TaggedString::Value`.
+pub trait TaggedHandler: Send + Sync {
+    fn parse(&self, content: &str) -> Result<TaggedString, String>;
+}
+
+impl<F: Fn(&str) -> Result<TaggedString, String> + Send + Sync> TaggedHandler for F {
+    fn parse(&self, content: &str) -> Result<TaggedString, String> {
+        self(content)
+    }
+}
+
+type Handlers = RwLock<BTreeMap<String, Arc<dyn TaggedHandler>>>;
+
+/// The handlers tagged literals are dispatched to, starting with the built-in ones.
+fn handlers() -> &'static Handlers {
+    static HANDLERS: OnceLock<Handlers> = OnceLock::new();
+    HANDLERS.get_or_init(|| {
+        let builtins: [(&str, Arc<dyn TaggedHandler>); 5] = [
+            ("re", Arc::new(regex)),
+            ("json", Arc::new(json)),
+            ("path", Arc::new(path)),
+            ("url", Arc::new(url)),
+            ("sh", Arc::new(sh)),
+        ];
+        let builtins = builtins.map(|(tag, handler)| (tag.to_string(), handler));
+        RwLock::new(BTreeMap::from(builtins))
+    })
+}
+
+/// Has `handler` build the `tag"..."` literals parsed from now on, replacing
+/// the handler for `tag` if there is one. Tags are made of ASCII letters.
+pub fn register(tag: &str, handler: impl TaggedHandler + 'static) -> Result<(), String> {
+    if tag.is_empty() || !tag.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(format!("invalid literal tag {tag:?}"));
+    }
+    let mut handlers = handlers().write().unwrap();
+    handlers.insert(tag.to_string(), Arc::new(handler));
+    Ok(())
+}
+
+/// Validates `content` with the handler registered for `tag`.
+pub fn parse(tag: &str, content: &str) -> Result<TaggedString, String> {
+    let handler = handlers().read().unwrap().get(tag).cloned();
+    let handler = handler.ok_or(format!("unknown literal tag {tag}"))?;
+    handler.parse(content)
+}
+
+fn regex(content: &str) -> Result<TaggedString, String> {
+    regex::Regex::new(content)
+        .map(TaggedString::Regex)
+        .map_err(|e| e.to_string())
+}
+
+fn json(content: &str) -> Result<TaggedString, String> {
+    let value: serde_json::Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+    check_json(&value)?;
+    Ok(TaggedString::Json(value))
+}
+
+/// haksh only has unsigned integers, so other numbers are rejected up front.
+fn check_json(value: &serde_json::Value) -> Result<(), String> {
+    match value {
+        serde_json::Value::Number(n) if n.as_u64().is_none() => {
+            Err(format!("unsupported number {n} in json literal"))
+        }
+        serde_json::Value::Array(items) => items.iter().try_for_each(check_json),
+        serde_json::Value::Object(fields) => fields.values().try_for_each(check_json),
+        _ => Ok(()),
+    }
+}
+
+fn path(content: &str) -> Result<TaggedString, String> {
+    if content.is_empty() || content.contains('\0') {
+        return Err(format!("invalid path {content:?}"));
+    }
+    Ok(TaggedString::Path(PathBuf::from(content)))
+}
+
+fn url(content: &str) -> Result<TaggedString, String> {
+    url::Url::parse(content)
+        .map(TaggedString::Url)
+        .map_err(|e| format!("invalid url {content:?}: {e}"))
+}
+
+fn sh(content: &str) -> Result<TaggedString, String> {
+    let words = split_words(content)?;
+    if words.is_empty() {
+        return Err("empty command".to_string());
+    }
+    Ok(TaggedString::Sh(words))
+}
+
+/// Splits a command line into words the way a POSIX shell would, honoring
+/// single quotes, double quotes and backslash escapes. Nothing is expanded.
+fn split_words(content: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = content.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated ' in command".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err("unterminated \" in command".to_string()),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated \" in command".to_string()),
+                    }
+                }
+            }
+            '\\' => {
+                let c = chars.next().ok_or("trailing \\ in command")?;
+                word.get_or_insert_with(String::new).push(c);
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[test]
+fn test_split_words() {
+    assert_eq!(
+        split_words(r#"curl -H 'a b' "x \"y\"" c\ d"#).unwrap(),
+        ["curl", "-H", "a b", "x \"y\"", "c d"]
+    );
+    assert_eq!(split_words("''").unwrap(), [""]);
+    assert!(split_words("echo 'oops").is_err());
+    assert!(parse("json", "{\"n\": -1}").is_err());
+    assert!(parse("url", "not a url").is_err());
+    assert!(parse("yaml", "a: 1").is_err());
+}
+
+#[test]
+fn test_register() {
+    use crate::interpreter::Value;
+    let upper = |content: &str| match content {
+        "" => Err("empty literal".to_string()),
+        _ => Ok(TaggedString::Value(Value::String(content.to_uppercase()))),
+    };
+    register("upper", upper).unwrap();
+    assert!(register("up per", upper).is_err());
+    assert!(matches!(
+        parse("upper", "abc"),
+        Ok(TaggedString::Value(Value::String(s))) if s == "ABC"
+    ));
+    assert_eq!(parse("upper", "").unwrap_err(), "empty literal");
+    // The parser consults the registry.
+    assert!(crate::parser::parse_file(r#"upper"abc""#).is_ok());
+    assert!(crate::parser::parse_file(r#"upper"""#).is_err());
+}
@@ -0,0 +1,171 @@
+use crate::interpreter::{Error, EvalResult, Value, STACK_SIZE};
+use crate::stream::Stream;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a blocked `join` or `recv` waits before checking for cancellation.
+const POLL: Duration = Duration::from_millis(100);
+
+thread_local! {
+    /// The cancel flag of the task running on this thread, if any.
+    static CURRENT: std::cell::RefCell<Option<Arc<AtomicBool>>> =
+        const { std::cell::RefCell::new(None) };
+}
+
+/// Whether the task running on this thread has been cancelled. It stays
+/// cancelled; like a cancelled script, it may still run its cleanup code.
+pub(crate) fn is_cancelled() -> bool {
+    CURRENT.with(|current| {
+        current
+            .borrow()
+            .as_ref()
+            .is_some_and(|flag| flag.load(Ordering::SeqCst))
+    })
+}
+
+/// Waits on `condvar` until `ready` returns a value, `deadline` passes or the
+/// current thread is cancelled. Returns `None` on timeout.
+fn wait_for<S, T>(
+    state: &Mutex<S>,
+    condvar: &Condvar,
+    deadline: Option<Instant>,
+    mut ready: impl FnMut(&mut S) -> Option<T>,
+) -> Result<Option<T>, Error> {
+    let mut guard = state.lock().unwrap();
+    loop {
+        if let Some(value) = ready(&mut guard) {
+            return Ok(Some(value));
+        }
+        let timeout = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => left.min(POLL),
+                None => return Ok(None),
+            },
+            None => POLL,
+        };
+        guard = condvar.wait_timeout(guard, timeout).unwrap().0;
+        drop(guard);
+        crate::interpreter::check_cancelled()?;
+        guard = state.lock().unwrap();
+    }
+}
+
+/// A handle to code started with `spawn`, running on its own OS thread.
+#[derive(Clone)]
+pub struct Task {
+    id: usize,
+    result: Arc<(Mutex<Option<EvalResult>>, Condvar)>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl std::fmt::Debug for Task {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Task({})", self.id)
+    }
+}
+
+impl Task {
+    pub fn spawn(run: impl FnOnce() -> EvalResult + Send + 'static) -> Result<Task, Error> {
+        static NEXT_ID: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(1);
+        let task = Task {
+            id: NEXT_ID.fetch_add(1, Ordering::SeqCst),
+            result: Arc::new((Mutex::new(None), Condvar::new())),
+            cancel: Arc::new(AtomicBool::new(false)),
+        };
+        let (result, cancel) = (task.result.clone(), task.cancel.clone());
+        std::thread::Builder::new()
+            .name(format!("task {}", task.id))
+            .stack_size(STACK_SIZE)
+            .spawn(move || {
+                CURRENT.with(|current| *current.borrow_mut() = Some(cancel));
+                // Sources the task registered are its to run.
+                let value = run().and_then(|v| crate::reactor::run().map(|_| v));
+                let (state, done) = &*result;
+                *state.lock().unwrap() = Some(value);
+                done.notify_all();
+            })
+            .map_err(|e| Error::new("task", e.to_string()))?;
+        Ok(task)
+    }
+
+    /// Waits for the task to finish and returns its value, or fails with its error.
+    pub fn join(&self) -> EvalResult {
+        let (state, done) = &*self.result;
+        wait_for(state, done, None, |result| result.clone())?.expect("no deadline")
+    }
+
+    /// Asks the task to stop; its next function call or wait fails with a
+    /// `cancelled` error.
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.result.0.lock().unwrap().is_some()
+    }
+}
+
+#[derive(Default)]
+struct Queue {
+    items: VecDeque<Value>,
+    closed: bool,
+}
+
+/// An unbounded queue of values for passing messages between tasks. Every
+/// copy refers to the same queue.
+#[derive(Clone, Default)]
+pub struct Channel(Arc<(Mutex<Queue>, Condvar)>);
+
+impl std::fmt::Debug for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Channel")
+    }
+}
+
+impl Channel {
+    pub fn new() -> Channel {
+        Channel::default()
+    }
+
+    pub fn send(&self, value: Value) -> Result<(), Error> {
+        let (queue, ready) = &*self.0;
+        let mut queue = queue.lock().unwrap();
+        if queue.closed {
+            return Err(Error::new("channel", "send on a closed channel"));
+        }
+        queue.items.push_back(value);
+        ready.notify_one();
+        Ok(())
+    }
+
+    /// Takes the oldest value, waiting for one if the queue is empty.
+    /// Returns `None` once the channel is closed and drained, or when
+    /// `timeout` passes first.
+    pub fn recv(&self, timeout: Option<Duration>) -> Result<Option<Value>, Error> {
+        let (queue, ready) = &*self.0;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let received = wait_for(queue, ready, deadline, |queue| {
+            match queue.items.pop_front() {
+                Some(value) => Some(Some(value)),
+                None if queue.closed => Some(None),
+                None => None,
+            }
+        })?;
+        Ok(received.flatten())
+    }
+
+    /// Stops further sends. Values already queued can still be received.
+    pub fn close(&self) {
+        let (queue, ready) = &*self.0;
+        queue.lock().unwrap().closed = true;
+        ready.notify_all();
+    }
+
+    /// The received values, ending when the channel is closed.
+    pub fn stream(&self) -> Stream {
+        let channel = self.clone();
+        Stream::new(std::iter::from_fn(move || channel.recv(None).transpose()))
+    }
+}
@@ -0,0 +1,590 @@
+//! Instants, durations and a strftime-style subset for parsing and formatting
+//! them. Time zones are fixed UTC offsets such as `+09:00`. There is no time
+//! zone database, so named zones like `Europe/Berlin` and their daylight
+//! saving changes aren't supported and are rejected.
+
+use std::cmp::Ordering;
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+const SECS_PER_DAY: i64 = 86_400;
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+const WEEKDAYS: [&str; 7] = [
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+    "Thursday",
+    "Friday",
+    "Saturday",
+];
+
+/// A signed span of time with nanosecond precision.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Duration(pub i64);
+
+impl Duration {
+    /// Parses the unit of a duration literal such as `5s` or `10m`.
+    pub fn from_unit(n: u64, unit: &str) -> Option<Duration> {
+        let scale: i64 = match unit {
+            "ms" => 1_000_000,
+            "s" => NANOS_PER_SEC,
+            "m" => 60 * NANOS_PER_SEC,
+            "h" => 3_600 * NANOS_PER_SEC,
+            "d" => SECS_PER_DAY * NANOS_PER_SEC,
+            _ => return None,
+        };
+        i64::try_from(n).ok()?.checked_mul(scale).map(Duration)
+    }
+
+    pub fn nanos(&self) -> i64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: Duration) -> Option<Duration> {
+        self.0.checked_add(other.0).map(Duration)
+    }
+
+    pub fn checked_sub(self, other: Duration) -> Option<Duration> {
+        self.0.checked_sub(other.0).map(Duration)
+    }
+
+    pub fn checked_mul(self, n: u64) -> Option<Duration> {
+        self.0.checked_mul(i64::try_from(n).ok()?).map(Duration)
+    }
+
+    pub fn checked_div(self, n: u64) -> Option<Duration> {
+        self.0.checked_div(i64::try_from(n).ok()?).map(Duration)
+    }
+
+    /// The duration as a `std` duration, or `None` if it is negative.
+    pub fn to_std(self) -> Option<std::time::Duration> {
+        u64::try_from(self.0)
+            .ok()
+            .map(std::time::Duration::from_nanos)
+    }
+}
+
+/// Written the way literals are: `1h30m`, `1.5s`, `250ms`.
+impl std::fmt::Display for Duration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0 < 0 {
+            write!(f, "-")?;
+        }
+        let nanos = self.0.unsigned_abs();
+        let secs = nanos / NANOS_PER_SEC as u64;
+        let sub = nanos % NANOS_PER_SEC as u64;
+        if secs == 0 {
+            return match sub {
+                0 => write!(f, "0s"),
+                n if n % 1_000_000 == 0 => write!(f, "{}ms", n / 1_000_000),
+                n => write!(f, "{n}ns"),
+            };
+        }
+        let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+        if hours > 0 {
+            write!(f, "{hours}h")?;
+        }
+        if minutes > 0 {
+            write!(f, "{minutes}m")?;
+        }
+        if secs > 0 || sub > 0 {
+            write!(f, "{secs}")?;
+            if sub > 0 {
+                let fraction = format!("{sub:09}");
+                write!(f, ".{}", fraction.trim_end_matches('0'))?;
+            }
+            write!(f, "s")?;
+        }
+        Ok(())
+    }
+}
+
+/// An instant, shown in a fixed UTC offset.
+#[derive(Debug, Clone, Copy)]
+pub struct DateTime {
+    /// Seconds since the Unix epoch.
+    secs: i64,
+    nanos: u32,
+    /// Seconds east of UTC.
+    offset: i32,
+}
+
+/// Instants compare by the moment they denote, whatever their offsets.
+impl PartialEq for DateTime {
+    fn eq(&self, other: &DateTime) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for DateTime {}
+
+impl PartialOrd for DateTime {
+    fn partial_cmp(&self, other: &DateTime) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for DateTime {
+    fn cmp(&self, other: &DateTime) -> Ordering {
+        (self.secs, self.nanos).cmp(&(other.secs, other.nanos))
+    }
+}
+
+/// RFC 3339, e.g. `2024-03-01T12:34:56+09:00`.
+impl std::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let format = if self.nanos == 0 {
+            "%Y-%m-%dT%H:%M:%S%:z"
+        } else {
+            "%Y-%m-%dT%H:%M:%S.%f%:z"
+        };
+        let s = self.format(format).map_err(|_| std::fmt::Error)?;
+        write!(f, "{s}")
+    }
+}
+
+/// Calendar fields of an instant in its own offset.
+struct Civil {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    /// 0 is Sunday.
+    weekday: usize,
+    /// 1 is January 1st.
+    ordinal: u32,
+}
+
+impl DateTime {
+    pub fn now() -> DateTime {
+        let since_epoch = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        DateTime {
+            secs: since_epoch.as_secs() as i64,
+            nanos: since_epoch.subsec_nanos(),
+            offset: 0,
+        }
+    }
+
+    /// The instant `secs` seconds after the Unix epoch, shown in `offset`.
+    pub fn from_unix(secs: i64, offset: i32) -> DateTime {
+        DateTime {
+            secs,
+            nanos: 0,
+            offset,
+        }
+    }
+
+    /// Seconds east of UTC the instant is shown in.
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub fn unix(&self) -> i64 {
+        self.secs
+    }
+
+    pub fn with_offset(&self, offset: i32) -> DateTime {
+        DateTime { offset, ..*self }
+    }
+
+    pub fn checked_add(&self, d: Duration) -> Option<DateTime> {
+        let nanos = (self.secs as i128 * NANOS_PER_SEC as i128)
+            .checked_add(self.nanos as i128)?
+            .checked_add(d.0 as i128)?;
+        Some(DateTime {
+            secs: i64::try_from(nanos.div_euclid(NANOS_PER_SEC as i128)).ok()?,
+            nanos: nanos.rem_euclid(NANOS_PER_SEC as i128) as u32,
+            offset: self.offset,
+        })
+    }
+
+    pub fn checked_sub(&self, d: Duration) -> Option<DateTime> {
+        self.checked_add(Duration(d.0.checked_neg()?))
+    }
+
+    /// The time from `earlier` to `self`; negative if `earlier` is later.
+    pub fn since(&self, earlier: &DateTime) -> Option<Duration> {
+        let secs = self.secs.checked_sub(earlier.secs)?;
+        let nanos = self.nanos as i64 - earlier.nanos as i64;
+        secs.checked_mul(NANOS_PER_SEC)?
+            .checked_add(nanos)
+            .map(Duration)
+    }
+
+    fn civil(&self) -> Result<Civil, String> {
+        let local = self
+            .secs
+            .checked_add(self.offset as i64)
+            .ok_or("time out of range")?;
+        let days = local.div_euclid(SECS_PER_DAY);
+        let secs = local.rem_euclid(SECS_PER_DAY) as u32;
+        let (year, month, day) = civil_from_days(days);
+        Ok(Civil {
+            year,
+            month,
+            day,
+            hour: secs / 3600,
+            minute: secs / 60 % 60,
+            second: secs % 60,
+            weekday: (days + 4).rem_euclid(7) as usize,
+            ordinal: (days - days_from_civil(year, 1, 1)) as u32 + 1,
+        })
+    }
+
+    /// The calendar field `name` (`year`, `month`, ... `weekday`) in the
+    /// instant's offset, or `None` if there is no such field.
+    pub fn field(&self, name: &str) -> Result<Option<i64>, String> {
+        let c = self.civil()?;
+        Ok(Some(match name {
+            "year" => c.year,
+            "month" => c.month.into(),
+            "day" => c.day.into(),
+            "hour" => c.hour.into(),
+            "minute" => c.minute.into(),
+            "second" => c.second.into(),
+            "weekday" => c.weekday as i64,
+            _ => return Ok(None),
+        }))
+    }
+
+    /// Formats with strftime-style specifiers: `%Y %m %d %e %H %M %S %f %3f
+    /// %6f %z %:z %Z %b %B %a %A %j %s %F %T %%`.
+    pub fn format(&self, format: &str) -> Result<String, String> {
+        let c = self.civil()?;
+        let mut out = String::new();
+        let mut chars = format.chars();
+        while let Some(ch) = chars.next() {
+            if ch != '%' {
+                out.push(ch);
+                continue;
+            }
+            let spec = specifier(&mut chars)?;
+            match spec.as_str() {
+                "Y" => out += &format!("{:04}", c.year),
+                "m" => out += &format!("{:02}", c.month),
+                "d" => out += &format!("{:02}", c.day),
+                "e" => out += &format!("{:2}", c.day),
+                "H" => out += &format!("{:02}", c.hour),
+                "M" => out += &format!("{:02}", c.minute),
+                "S" => out += &format!("{:02}", c.second),
+                "f" => out += &format!("{:09}", self.nanos),
+                "3f" => out += &format!("{:03}", self.nanos / 1_000_000),
+                "6f" => out += &format!("{:06}", self.nanos / 1_000),
+                "z" => out += &offset_string(self.offset, ""),
+                ":z" => out += &offset_string(self.offset, ":"),
+                "Z" if self.offset == 0 => out += "UTC",
+                "Z" => out += &offset_string(self.offset, ":"),
+                "b" => out += &MONTHS[c.month as usize - 1][..3],
+                "B" => out += MONTHS[c.month as usize - 1],
+                "a" => out += &WEEKDAYS[c.weekday][..3],
+                "A" => out += WEEKDAYS[c.weekday],
+                "j" => out += &format!("{:03}", c.ordinal),
+                "s" => out += &self.secs.to_string(),
+                "F" => out += &self.format("%Y-%m-%d")?,
+                "T" => out += &self.format("%H:%M:%S")?,
+                "%" => out.push('%'),
+                spec => return Err(format!("unknown format specifier %{spec}")),
+            }
+        }
+        Ok(out)
+    }
+
+    /// Parses `input` with the specifiers `format` supports. A timestamp
+    /// without `%z` is taken to be in `offset`.
+    pub fn parse(input: &str, format: &str, offset: i32) -> Result<DateTime, String> {
+        let fail = || format!("{input:?} does not match {format:?}");
+        let mut fields = Fields {
+            year: 1970,
+            month: 1,
+            day: 1,
+            hour: 0,
+            minute: 0,
+            second: 0,
+            nanos: 0,
+            offset,
+            unix: None,
+        };
+        let rest = parse_into(input, format, &mut fields).ok_or_else(fail)?;
+        if !rest.is_empty() {
+            return Err(format!("unexpected {rest:?} after timestamp"));
+        }
+        if let Some(secs) = fields.unix {
+            return Ok(DateTime {
+                secs,
+                nanos: fields.nanos,
+                offset: fields.offset,
+            });
+        }
+        let valid = (1..=12).contains(&fields.month)
+            && fields.day >= 1
+            && fields.day <= days_in_month(fields.year, fields.month)
+            && fields.hour < 24
+            && fields.minute < 60
+            && fields.second < 60;
+        if !valid {
+            return Err(format!("{input:?} is not a valid date and time"));
+        }
+        let days = days_from_civil(fields.year, fields.month, fields.day);
+        let local =
+            days * SECS_PER_DAY + (fields.hour * 3600 + fields.minute * 60 + fields.second) as i64;
+        Ok(DateTime {
+            secs: local - fields.offset as i64,
+            nanos: fields.nanos,
+            offset: fields.offset,
+        })
+    }
+
+    /// Parses RFC 3339, with or without fractional seconds.
+    pub fn parse_rfc3339(input: &str) -> Result<DateTime, String> {
+        DateTime::parse(input, "%Y-%m-%dT%H:%M:%S%z", 0)
+            .or_else(|_| DateTime::parse(input, "%Y-%m-%dT%H:%M:%S.%f%z", 0))
+            .map_err(|_| format!("{input:?} is not an RFC 3339 timestamp"))
+    }
+}
+
+struct Fields {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+    nanos: u32,
+    offset: i32,
+    unix: Option<i64>,
+}
+
+/// Reads the specifier after a `%`: a letter, optionally preceded by `:` or a digit.
+fn specifier(chars: &mut std::str::Chars) -> Result<String, String> {
+    let mut spec = String::new();
+    for c in chars.by_ref() {
+        spec.push(c);
+        if c != ':' && !c.is_ascii_digit() {
+            return Ok(spec);
+        }
+    }
+    Err("format ends with %".to_string())
+}
+
+/// Consumes `input` according to `format`, returning what is left of `input`.
+fn parse_into<'a>(mut input: &'a str, format: &str, fields: &mut Fields) -> Option<&'a str> {
+    let mut chars = format.chars();
+    while let Some(ch) = chars.next() {
+        if ch != '%' {
+            input = input.strip_prefix(ch)?;
+            continue;
+        }
+        let spec = specifier(&mut chars).ok()?;
+        input = match spec.as_str() {
+            "Y" => {
+                let (n, rest) = number(input, 4)?;
+                fields.year = n;
+                rest
+            }
+            "m" => two_digits(input, &mut fields.month)?,
+            "d" => two_digits(input, &mut fields.day)?,
+            "e" => two_digits(input.trim_start_matches(' '), &mut fields.day)?,
+            "H" => two_digits(input, &mut fields.hour)?,
+            "M" => two_digits(input, &mut fields.minute)?,
+            "S" => two_digits(input, &mut fields.second)?,
+            "f" | "3f" | "6f" => {
+                let digits =
+                    input.len() - input.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                if digits == 0 || digits > 9 {
+                    return None;
+                }
+                let (fraction, rest) = input.split_at(digits);
+                fields.nanos = format!("{fraction:0<9}").parse().ok()?;
+                rest
+            }
+            "z" | ":z" | "Z" => {
+                let (offset, rest) = parse_offset_prefix(input)?;
+                fields.offset = offset;
+                rest
+            }
+            "b" | "B" => {
+                let (index, rest) = name(input, &MONTHS, spec == "b")?;
+                fields.month = index as u32 + 1;
+                rest
+            }
+            "a" | "A" => name(input, &WEEKDAYS, spec == "a")?.1,
+            "s" => {
+                let (n, rest) = number(input, 19)?;
+                fields.unix = Some(n);
+                rest
+            }
+            "F" => parse_into(input, "%Y-%m-%d", fields)?,
+            "T" => parse_into(input, "%H:%M:%S", fields)?,
+            "%" => input.strip_prefix('%')?,
+            _ => return None,
+        };
+    }
+    Some(input)
+}
+
+/// An optionally signed decimal number of at most `max` digits.
+fn number(input: &str, max: usize) -> Option<(i64, &str)> {
+    let (sign, unsigned) = match input.strip_prefix('-') {
+        Some(rest) => (-1, rest),
+        None => (1, input),
+    };
+    let digits = unsigned
+        .chars()
+        .take(max)
+        .take_while(|c| c.is_ascii_digit())
+        .count();
+    if digits == 0 {
+        return None;
+    }
+    let (n, rest) = unsigned.split_at(digits);
+    Some((sign * n.parse::<i64>().ok()?, rest))
+}
+
+fn two_digits<'a>(input: &'a str, field: &mut u32) -> Option<&'a str> {
+    if input.starts_with('-') {
+        return None;
+    }
+    let (n, rest) = number(input, 2)?;
+    *field = n as u32;
+    Some(rest)
+}
+
+/// Matches a month or weekday name, full or abbreviated, ignoring case.
+fn name<'a>(input: &'a str, names: &[&str], short: bool) -> Option<(usize, &'a str)> {
+    names.iter().enumerate().find_map(|(index, name)| {
+        let name = if short { &name[..3] } else { name };
+        let head = input.get(..name.len())?;
+        head.eq_ignore_ascii_case(name)
+            .then(|| (index, &input[name.len()..]))
+    })
+}
+
+/// Parses a time zone: `UTC`, `Z`, `+09:00`, `+0900` or `-05`.
+pub fn parse_offset(input: &str) -> Result<i32, String> {
+    match parse_offset_prefix(input) {
+        Some((offset, "")) => Ok(offset),
+        // `Europe/Berlin`, `CET` and the like.
+        _ if input.starts_with(|c: char| c.is_ascii_alphabetic()) => Err(format!(
+            "named time zone {input:?} is not supported, use a UTC offset such as +01:00"
+        )),
+        _ => Err(format!("unknown time zone {input:?}")),
+    }
+}
+
+fn parse_offset_prefix(input: &str) -> Option<(i32, &str)> {
+    if let Some(rest) = input
+        .strip_prefix("UTC")
+        .or_else(|| input.strip_prefix('Z'))
+    {
+        if !rest.starts_with(['+', '-']) {
+            return Some((0, rest));
+        }
+        return parse_offset_prefix(rest);
+    }
+    let sign = match input.chars().next()? {
+        '+' => 1,
+        '-' => -1,
+        _ => return None,
+    };
+    let mut hours = 0;
+    let rest = two_digits(&input[1..], &mut hours)?;
+    let rest = rest.strip_prefix(':').unwrap_or(rest);
+    let mut minutes = 0;
+    let rest = match two_digits(rest, &mut minutes) {
+        Some(rest) => rest,
+        None => rest,
+    };
+    if hours > 23 || minutes > 59 {
+        return None;
+    }
+    Some((sign * (hours * 3600 + minutes * 60) as i32, rest))
+}
+
+fn offset_string(offset: i32, separator: &str) -> String {
+    let sign = if offset < 0 { '-' } else { '+' };
+    let minutes = offset.unsigned_abs() / 60;
+    format!("{sign}{:02}{separator}{:02}", minutes / 60, minutes % 60)
+}
+
+fn is_leap(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if is_leap(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// The inverse of [`days_from_civil`].
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[test]
+fn test_time() {
+    let t = DateTime::parse_rfc3339("2024-02-29T23:59:30+09:00").unwrap();
+    assert_eq!(t.unix(), 1_709_218_770);
+    assert_eq!(t.to_string(), "2024-02-29T23:59:30+09:00");
+    assert_eq!(t.with_offset(0).to_string(), "2024-02-29T14:59:30+00:00");
+    assert_eq!(
+        t.checked_add(Duration::from_unit(1, "m").unwrap())
+            .unwrap()
+            .format("%a %d %b %Y %T %Z")
+            .unwrap(),
+        "Fri 01 Mar 2024 00:00:30 +09:00"
+    );
+
+    let log = DateTime::parse("[01/Mar/2024:00:00:30.5 +0900]", "[%d/%b/%Y:%T.%f %z]", 0).unwrap();
+    assert_eq!(log.since(&t), Some(Duration(60_500_000_000)));
+    assert_eq!(Duration(60_500_000_000).to_string(), "1m0.5s");
+    assert!(DateTime::parse("2023-02-29", "%F", 0).is_err());
+    assert_eq!(parse_offset("-05"), Ok(-5 * 3600));
+    assert!(parse_offset("Europe/Berlin")
+        .unwrap_err()
+        .contains("named time zone"));
+    let last = DateTime::from_unix(i64::MAX, 3600);
+    assert!(last.format("%F").is_err());
+    assert!(last.field("year").is_err());
+}
@@ -0,0 +1,118 @@
+use crate::interpreter::{Error, Value};
+use crate::reactor::{Emitter, Guard};
+use crate::time::DateTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often a waiting timer checks whether it was stopped.
+const POLL: Duration = Duration::from_millis(50);
+
+/// Sleeps until `deadline`, returning false early if `stopped` gets set.
+pub(crate) fn wait_until(deadline: Instant, stopped: &AtomicBool) -> bool {
+    loop {
+        if stopped.load(Ordering::SeqCst) {
+            return false;
+        }
+        match deadline.checked_duration_since(Instant::now()) {
+            Some(left) if !left.is_zero() => std::thread::sleep(left.min(POLL)),
+            _ => return true,
+        }
+    }
+}
+
+/// Blocks the current thread for `duration`, failing early if the script or
+/// task is cancelled.
+pub fn sleep(duration: Duration) -> Result<(), Error> {
+    // A duration too long for an `Instant` lasts until cancelled.
+    let deadline = Instant::now().checked_add(duration);
+    loop {
+        let left = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => left,
+                None => return Ok(()),
+            },
+            None => POLL,
+        };
+        std::thread::sleep(left.min(POLL));
+        crate::interpreter::check_cancelled()?;
+    }
+}
+
+/// Calls back once, with the current time, after `delay`.
+pub fn after(delay: Duration, emitter: Emitter) -> Guard {
+    let (guard, stopped) = crate::reactor::stop_flag();
+    // A delay too long for an `Instant` never ends.
+    let Some(deadline) = Instant::now().checked_add(delay) else {
+        return guard;
+    };
+    std::thread::spawn(move || {
+        if wait_until(deadline, &stopped) {
+            emitter.emit(Value::DateTime(DateTime::now()));
+        }
+    });
+    guard
+}
+
+/// Calls back with the current time every `interval`. Ticks are scheduled
+/// from the start, so a slow callback doesn't make later ones drift.
+pub fn every(interval: Duration, emitter: Emitter) -> Guard {
+    let (guard, stopped) = crate::reactor::stop_flag();
+    let start = Instant::now();
+    std::thread::spawn(move || {
+        for tick in 1u64.. {
+            // Ticks stop once they are further off than an `Instant` can be.
+            let Some(deadline) = nth_tick(start, interval, tick) else {
+                return;
+            };
+            if !wait_until(deadline, &stopped) || !emitter.emit(Value::DateTime(DateTime::now())) {
+                return;
+            }
+        }
+    });
+    guard
+}
+
+/// When tick `n` of an interval timer started at `start` is due.
+fn nth_tick(start: Instant, interval: Duration, n: u64) -> Option<Instant> {
+    let nanos = interval.as_nanos().checked_mul(n.into())?;
+    let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+    start.checked_add(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
+/// Calls back with the scheduled time whenever `schedule` matches the wall
+/// clock in `offset`.
+pub fn cron(schedule: crate::cron::Schedule, offset: i32, emitter: Emitter) -> Guard {
+    let (guard, stopped) = crate::reactor::stop_flag();
+    std::thread::spawn(move || {
+        let mut now = DateTime::now().with_offset(offset);
+        while let Some(next) = schedule.next_after(&now) {
+            // Wake up now and then in case the wall clock was changed.
+            loop {
+                let left = next
+                    .since(&DateTime::now())
+                    .and_then(|d| d.to_std())
+                    .unwrap_or_default();
+                if left.is_zero() {
+                    break;
+                }
+                if !wait_until(Instant::now() + left.min(Duration::from_secs(60)), &stopped) {
+                    return;
+                }
+            }
+            if !emitter.emit(Value::DateTime(next)) {
+                return;
+            }
+            now = next;
+        }
+    });
+    guard
+}
+
+#[test]
+fn test_nth_tick() {
+    let start = Instant::now();
+    let ms = Duration::from_millis(1);
+    let tick = nth_tick(start, ms, u64::from(u32::MAX) + 1).unwrap();
+    assert_eq!(tick - start, ms * u32::MAX + ms);
+    assert_eq!(nth_tick(start, Duration::MAX, 2), None);
+}
@@ -0,0 +1,706 @@
+use crate::ast::*;
+use std::collections::BTreeMap;
+
+/// The static type of an expression. `Any` stands for everything the checker
+/// can't see through, such as imports, prelude functions and `let mut`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Type {
+    Any,
+    Unit,
+    None,
+    Int,
+    Bool,
+    String,
+    Range,
+    Stream,
+    Task,
+    Channel,
+    DateTime,
+    Duration,
+    Regex,
+    Path,
+    Url,
+    Command,
+    Error,
+    List(Box<Type>),
+    Compound(BTreeMap<String, Type>),
+    Fn { params: Vec<Type>, ret: Box<Type> },
+}
+
+impl Type {
+    /// Whether a value of type `self` may be used where `expected` is required.
+    fn fits(&self, expected: &Type) -> bool {
+        match (self, expected) {
+            (Type::Any, _) | (_, Type::Any) => true,
+            (Type::List(a), Type::List(b)) => a.fits(b),
+            // Fields the expected type doesn't list are allowed; missing ones are none.
+            (Type::Compound(actual), Type::Compound(expected)) => expected
+                .iter()
+                .all(|(k, t)| actual.get(k).unwrap_or(&Type::None).fits(t)),
+            (Type::Fn { .. }, Type::Fn { .. }) => true,
+            (a, b) => a == b,
+        }
+    }
+
+    /// The type of a value that is either `self` or `other`.
+    fn join(self, other: Type) -> Type {
+        if self == other {
+            self
+        } else {
+            Type::Any
+        }
+    }
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Any => write!(f, "Any"),
+            Type::Unit => write!(f, "Unit"),
+            Type::None => write!(f, "None"),
+            Type::Int => write!(f, "Int"),
+            Type::Bool => write!(f, "Bool"),
+            Type::String => write!(f, "String"),
+            Type::Range => write!(f, "Range"),
+            Type::Stream => write!(f, "Stream"),
+            Type::Task => write!(f, "Task"),
+            Type::Channel => write!(f, "Channel"),
+            Type::DateTime => write!(f, "DateTime"),
+            Type::Duration => write!(f, "Duration"),
+            Type::Regex => write!(f, "Regex"),
+            Type::Path => write!(f, "Path"),
+            Type::Url => write!(f, "Url"),
+            Type::Command => write!(f, "Command"),
+            Type::Error => write!(f, "Error"),
+            Type::List(t) => write!(f, "[{t}]"),
+            Type::Compound(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|(k, t)| format!("{k}: {t}"))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(f, "({fields})")
+            }
+            Type::Fn { params, .. } => write!(f, "Fn/{}", params.len()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeError {
+    pub message: String,
+}
+
+impl std::fmt::Display for TypeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "type error: {}", self.message)
+    }
+}
+
+/// Infers the type of `block` and reports every type error found in it,
+/// including in branches and callbacks that might never run.
+pub fn check(block: &Block) -> Result<Type, Vec<TypeError>> {
+    let mut checker = Checker { errors: Vec::new() };
+    let t = checker.block(&block.0, &Scope::new());
+    if checker.errors.is_empty() {
+        Ok(t)
+    } else {
+        Err(checker.errors)
+    }
+}
+
+type Scope = BTreeMap<String, Type>;
+
+struct Checker {
+    errors: Vec<TypeError>,
+}
+
+impl Checker {
+    fn error(&mut self, message: String) {
+        self.errors.push(TypeError { message });
+    }
+
+    fn expect(&mut self, t: &Type, expected: &Type, what: &str) {
+        if !t.fits(expected) {
+            self.error(format!("{what} is {t}, expected {expected}"));
+        }
+    }
+
+    fn block(&mut self, elements: &[BlockElement], scope: &Scope) -> Type {
+        let mut scope = scope.clone();
+        let mut t = Type::Unit;
+        for (index, e) in elements.iter().enumerate() {
+            if let BlockElement::Using { name, def } = e {
+                // The rest of the block is the callback `def` is given.
+                let param = if def.fident.is(&["fs", "watch"]) {
+                    Type::String
+                } else {
+                    Type::Any
+                };
+                self.application(def, &scope);
+                scope.insert(name.clone(), param);
+                self.block(&elements[index + 1..], &scope);
+                return Type::Any;
+            }
+            t = self.element(e, &mut scope);
+        }
+        t
+    }
+
+    fn element(&mut self, e: &BlockElement, scope: &mut Scope) -> Type {
+        match e {
+            BlockElement::Expr(e) => self.expr(e, scope),
+            BlockElement::Var {
+                pattern,
+                def,
+                mutable,
+            } => {
+                let t = self.expr(def, scope);
+                self.bind(pattern, t, scope);
+                if *mutable {
+                    // A mutable binding may be reassigned to anything later on.
+                    for name in pattern.names() {
+                        scope.insert(name.to_string(), Type::Any);
+                    }
+                }
+                Type::Unit
+            }
+            BlockElement::Assign { def, .. } => {
+                self.expr(def, scope);
+                Type::Unit
+            }
+            BlockElement::AnonymousFunction(f) => self.function(f, None, scope),
+            BlockElement::Fn { name, function } => {
+                let t = self.function(function, Some(name), scope);
+                scope.insert(name.clone(), t);
+                Type::Unit
+            }
+            BlockElement::Import { name, .. } => {
+                scope.insert(name.clone(), Type::Any);
+                Type::Unit
+            }
+            BlockElement::Export(e) => self.element(e, scope),
+            BlockElement::Defer(b) => {
+                self.block(&b.0, scope);
+                Type::Unit
+            }
+            BlockElement::Using { .. } => unreachable!("using is checked by its block"),
+        }
+    }
+
+    fn function(&mut self, f: &AnonymousFunction, name: Option<&str>, scope: &Scope) -> Type {
+        let mut scope = scope.clone();
+        let params: Vec<Type> = f
+            .params
+            .iter()
+            .map(|p| match p {
+                Pattern::Annotated { ty, .. } => self.annotation(ty),
+                _ => Type::Any,
+            })
+            .collect();
+        if let Some(name) = name {
+            // Recursive calls can't know the return type yet.
+            let t = Type::Fn {
+                params: params.clone(),
+                ret: Box::new(Type::Any),
+            };
+            scope.insert(name.to_string(), t);
+        }
+        for p in &f.params {
+            self.bind(p, Type::Any, &mut scope);
+        }
+        let ret = self.block(&f.body.0, &scope);
+        Type::Fn {
+            params,
+            ret: Box::new(ret),
+        }
+    }
+
+    /// The type an annotation stands for. Optional types are unchecked.
+    fn annotation(&mut self, ty: &TypeExpr) -> Type {
+        match ty {
+            TypeExpr::Named(name) => match name.as_str() {
+                "Any" => Type::Any,
+                "Unit" => Type::Unit,
+                "None" => Type::None,
+                "Int" => Type::Int,
+                "Bool" => Type::Bool,
+                "String" => Type::String,
+                "Range" => Type::Range,
+                "Stream" => Type::Stream,
+                "Task" => Type::Task,
+                "Channel" => Type::Channel,
+                "DateTime" => Type::DateTime,
+                "Duration" => Type::Duration,
+                "Regex" => Type::Regex,
+                "Path" => Type::Path,
+                "Url" => Type::Url,
+                "Command" => Type::Command,
+                "Error" => Type::Error,
+                "Fn" => Type::Fn {
+                    params: Vec::new(),
+                    ret: Box::new(Type::Any),
+                },
+                _ => {
+                    self.error(format!("unknown type {name}"));
+                    Type::Any
+                }
+            },
+            TypeExpr::List(item) => Type::List(Box::new(self.annotation(item))),
+            TypeExpr::Compound(fields) => Type::Compound(
+                fields
+                    .iter()
+                    .map(|(k, t)| (k.clone(), self.annotation(t)))
+                    .collect(),
+            ),
+            TypeExpr::Optional(t) => {
+                self.annotation(t);
+                Type::Any
+            }
+        }
+    }
+
+    fn bind(&mut self, pattern: &Pattern, t: Type, scope: &mut Scope) {
+        match pattern {
+            Pattern::Name(name) => {
+                scope.insert(name.clone(), t);
+            }
+            Pattern::Compound(fields) => {
+                self.expect(&t, &Type::Compound(BTreeMap::new()), "destructured value");
+                for (key, pattern) in fields {
+                    let field = match &t {
+                        Type::Compound(known) => known.get(key).cloned().unwrap_or(Type::Any),
+                        _ => Type::Any,
+                    };
+                    self.bind(pattern, field, scope);
+                }
+            }
+            Pattern::List { items, rest } => {
+                self.expect(&t, &Type::List(Box::new(Type::Any)), "destructured value");
+                let item = match &t {
+                    Type::List(item) => (**item).clone(),
+                    _ => Type::Any,
+                };
+                for pattern in items {
+                    self.bind(pattern, item.clone(), scope);
+                }
+                if let Some(rest) = rest {
+                    scope.insert(rest.clone(), Type::List(Box::new(item)));
+                }
+            }
+            Pattern::Annotated { pattern, ty } => {
+                let declared = self.annotation(ty);
+                let what = pattern.names().join(", ");
+                self.expect(&t, &declared, &what);
+                self.bind(pattern, declared, scope);
+            }
+        }
+    }
+
+    fn expr(&mut self, e: &Expr, scope: &Scope) -> Type {
+        match e {
+            Expr::AddSub(e) => {
+                let op = match e.op {
+                    AddSubOp::Add => "+",
+                    AddSubOp::Sub => "-",
+                };
+                self.arithmetic(&e.left, &e.right, op, scope)
+            }
+            Expr::MulDiv(e) => {
+                let op = match e.op {
+                    MulDivOp::Mul => "*",
+                    MulDivOp::Div => "/",
+                };
+                self.arithmetic(&e.left, &e.right, op, scope)
+            }
+            Expr::Compare(e) => {
+                let left = self.expr(&e.left, scope);
+                let right = self.expr(&e.right, scope);
+                let comparable =
+                    left.fits(&right) || matches!(left, Type::None) || matches!(right, Type::None);
+                let orderable = |t: &Type| {
+                    !matches!(
+                        t,
+                        Type::List(_) | Type::Compound(_) | Type::Fn { .. } | Type::None
+                    )
+                };
+                let ordering = !matches!(e.op, CompareOp::Eq | CompareOp::Ne);
+                if !comparable {
+                    self.error(format!("cannot compare {left} with {right}"));
+                } else if ordering && !(orderable(&left) && orderable(&right)) {
+                    self.error(format!("cannot order {left} and {right}"));
+                }
+                Type::Bool
+            }
+            Expr::Primary(e) => self.primary(e, scope),
+            Expr::FunctionApplication(f) => self.application(f, scope),
+            Expr::If(e) => {
+                let cond = self.expr(&e.cond, scope);
+                self.expect(&cond, &Type::Bool, "`if` condition");
+                let t = self.expr(&e.true_exp, scope);
+                t.join(self.expr(&e.false_expr, scope))
+            }
+            Expr::Try(e) => {
+                let mut t = self.block(&e.body.0, scope);
+                if let Some(catch) = &e.catch {
+                    let mut handler_scope = scope.clone();
+                    handler_scope.insert(catch.name.clone(), Type::Error);
+                    t = t.join(self.block(&catch.handler.0, &handler_scope));
+                }
+                if let Some(finally) = &e.finally {
+                    self.block(&finally.0, scope);
+                }
+                t
+            }
+            Expr::Propagate(e) => match self.expr(e, scope) {
+                Type::Error => Type::Any,
+                t => t,
+            },
+            Expr::Range(e) => {
+                for bound in [&e.start, &e.end] {
+                    let t = self.expr(bound, scope);
+                    self.expect(&t, &Type::Int, "range bound");
+                }
+                if let Some(step) = &e.step {
+                    let t = self.primary(step, scope);
+                    self.expect(&t, &Type::Int, "range step");
+                }
+                Type::Range
+            }
+            Expr::Coalesce(e) => match self.expr(&e.value, scope) {
+                Type::None => self.expr(&e.default, scope),
+                t => {
+                    let default = self.expr(&e.default, scope);
+                    t.join(default)
+                }
+            },
+        }
+    }
+
+    fn arithmetic(&mut self, left: &Expr, right: &Expr, op: &str, scope: &Scope) -> Type {
+        use Type::{Any, DateTime, Duration, Int, Path};
+        let left = self.expr(left, scope);
+        let right = self.expr(right, scope);
+        match (op, &left, &right) {
+            ("/", Path, right) => {
+                self.path_like(right, "joined path");
+                Path
+            }
+            // An unknown operand may be of any type that works at runtime.
+            (_, Any, _) | (_, _, Any) => Any,
+            ("+", Int, Int) | ("-", Int, Int) | ("*", Int, Int) | ("/", Int, Int) => Int,
+            ("+", DateTime, Duration) | ("+", Duration, DateTime) | ("-", DateTime, Duration) => {
+                DateTime
+            }
+            ("-", DateTime, DateTime)
+            | ("+" | "-", Duration, Duration)
+            | ("*", Duration, Int)
+            | ("*", Int, Duration)
+            | ("/", Duration, Int) => Duration,
+            _ => {
+                self.error(format!("cannot apply {op} to {left} and {right}"));
+                Any
+            }
+        }
+    }
+
+    fn primary(&mut self, e: &PrimaryExpr, scope: &Scope) -> Type {
+        match e {
+            PrimaryExpr::Bool(_) => Type::Bool,
+            PrimaryExpr::Block(b) => self.block(&b.0, scope),
+            PrimaryExpr::DecimalInt(_) => Type::Int,
+            PrimaryExpr::Duration(_) => Type::Duration,
+            PrimaryExpr::Identifier(name) => scope.get(name).cloned().unwrap_or(Type::Any),
+            PrimaryExpr::StringLiteral(_) => Type::String,
+            PrimaryExpr::None => Type::None,
+            PrimaryExpr::TaggedString(ts) => match ts {
+                TaggedString::Regex(_) => Type::Regex,
+                TaggedString::Json(json) => json_type(json),
+                TaggedString::Path(_) => Type::Path,
+                TaggedString::Url(_) => Type::Url,
+                TaggedString::Sh(_) => Type::Command,
+                TaggedString::Value(_) => Type::Any,
+            },
+            PrimaryExpr::Compound(entries) => {
+                let mut fields = BTreeMap::new();
+                // Spreading a compound of unknown shape leaves the result unknown too.
+                let mut known = true;
+                for entry in entries {
+                    match entry {
+                        CompoundEntry::Spread(base) => match self.expr(base, scope) {
+                            Type::Compound(base) => fields.extend(base),
+                            t => {
+                                self.expect(&t, &Type::Compound(BTreeMap::new()), "spread value");
+                                known = false;
+                            }
+                        },
+                        CompoundEntry::Field { path, def } => {
+                            let t = self.expr(def, scope);
+                            let t = if path.len() == 1 { t } else { Type::Any };
+                            fields.insert(path[0].clone(), t);
+                        }
+                    }
+                }
+                if known {
+                    Type::Compound(fields)
+                } else {
+                    Type::Any
+                }
+            }
+            PrimaryExpr::List(items) => {
+                let item = items
+                    .iter()
+                    .map(|e| self.expr(e, scope))
+                    .reduce(Type::join)
+                    .unwrap_or(Type::Any);
+                Type::List(Box::new(item))
+            }
+        }
+    }
+
+    fn application(&mut self, f: &FunctionApplication, scope: &Scope) -> Type {
+        let args: Vec<Type> = f.args.iter().map(|a| self.primary(a, scope)).collect();
+        for option in f.options.values() {
+            self.primary(option, scope);
+        }
+        let arg = |index: usize| args.get(index).cloned().unwrap_or(Type::Any);
+
+        let id = &f.fident;
+        if id.is(&["fs", "cwd"]) {
+            Type::Path
+        } else if id.is(&["println"]) {
+            Type::Unit
+        } else if id.is(&["http", "get"]) {
+            self.url(&arg(0), "http.get url");
+            Type::String
+        } else if id.is(&["http", "post", "json"]) {
+            self.url(&arg(0), "http.post.json url");
+            let body = arg(1);
+            self.expect(
+                &body,
+                &Type::Compound(BTreeMap::new()),
+                "http.post.json body",
+            );
+            Type::String
+        } else if id.is(&["http", "serve"]) {
+            self.expect(&arg(0), &Type::Int, "http.serve port");
+            Type::Unit
+        } else if id.is(&["fs", "watch"]) {
+            match arg(0) {
+                Type::List(path) => self.path_like(&path, "fs.watch path"),
+                path => self.path_like(&path, "fs.watch path"),
+            }
+            if args.len() == 1 {
+                Type::Stream
+            } else {
+                Type::Unit
+            }
+        } else if id.is(&["spawn"]) && !scope.contains_key("spawn") {
+            Type::Task
+        } else if id.is(&["join"]) && !scope.contains_key("join") {
+            if !arg(0).fits(&Type::Task) && !arg(0).fits(&Type::List(Box::new(Type::Task))) {
+                self.error(format!(
+                    "join argument is {}, expected Task or [Task]",
+                    arg(0)
+                ));
+            }
+            Type::Any
+        } else if id.is(&["chan"]) && !scope.contains_key("chan") {
+            Type::Channel
+        } else if id.is(&["send"]) && !scope.contains_key("send") {
+            self.expect(&arg(0), &Type::Channel, "send channel");
+            Type::Unit
+        } else if id.is(&["recv"]) && !scope.contains_key("recv") {
+            self.expect(&arg(0), &Type::Channel, "recv channel");
+            Type::Any
+        } else if id.is(&["sleep"]) && !scope.contains_key("sleep") {
+            self.expect(&arg(0), &Type::Duration, "sleep duration");
+            Type::Unit
+        } else if (id.is(&["after"]) || id.is(&["every"])) && !scope.contains_key(&id.path) {
+            self.expect(&arg(0), &Type::Duration, &format!("{} duration", id.path));
+            Type::Unit
+        } else if id.is(&["cron"]) && !scope.contains_key("cron") {
+            self.expect(&arg(0), &Type::String, "cron schedule");
+            Type::Unit
+        } else if (id.is(&["debounce"]) || id.is(&["throttle"]) || id.is(&["batch"]))
+            && !scope.contains_key(&id.path)
+        {
+            if !id.is(&["batch"]) {
+                self.expect(&arg(0), &Type::Duration, &format!("{} window", id.path));
+            }
+            Type::Fn {
+                params: vec![Type::Any],
+                ret: Box::new(Type::Any),
+            }
+        } else if id.is(&["time", "now"]) {
+            Type::DateTime
+        } else if id.is(&["time", "parse"]) {
+            self.expect(&arg(0), &Type::String, "time.parse input");
+            if args.len() > 1 {
+                self.expect(&arg(1), &Type::String, "time.parse format");
+            }
+            Type::DateTime
+        } else if id.is(&["env", "var"]) {
+            self.expect(&arg(0), &Type::String, "env.var name");
+            Type::Any
+        } else if id.is(&["throw"]) && !scope.contains_key("throw") {
+            Type::Any
+        } else if id.is(&["error"]) && !scope.contains_key("error") {
+            Type::Error
+        } else {
+            self.path(id, &args, scope)
+        }
+    }
+
+    fn path_like(&mut self, t: &Type, what: &str) {
+        if !t.fits(&Type::String) && *t != Type::Path {
+            self.error(format!("{what} is {t}, expected String or Path"));
+        }
+    }
+
+    fn url(&mut self, t: &Type, what: &str) {
+        if !t.fits(&Type::String) && *t != Type::Url {
+            self.error(format!("{what} is {t}, expected String or Url"));
+        }
+    }
+
+    /// The type of a name or dotted path applied to `args`.
+    fn path(&mut self, id: &Identifier, args: &[Type], scope: &Scope) -> Type {
+        let mut t = scope.get(&id.path).cloned().unwrap_or(Type::Any);
+        let mut child = id.child.as_deref();
+        while let Some(c) = child {
+            t = match (&t, c.path.as_str()) {
+                (Type::Compound(fields), name) => fields.get(name).cloned().unwrap_or(Type::Any),
+                (Type::Error, "kind" | "message") => Type::String,
+                (Type::String, "includes") => Type::Bool,
+                (Type::Regex, "is_match") => Type::Bool,
+                (Type::Regex, "replace") => Type::String,
+                (Type::Regex, "find_all" | "split") => Type::List(Box::new(Type::String)),
+                (Type::Url, "scheme" | "path") => Type::String,
+                (Type::Path, "join" | "with_extension" | "canonicalize") => Type::Path,
+                (Type::Path, "to_string") => Type::String,
+                (Type::DateTime, "format") => Type::String,
+                (Type::DateTime, "to_tz") => Type::DateTime,
+                (
+                    Type::DateTime,
+                    "unix" | "year" | "month" | "day" | "hour" | "minute" | "second" | "weekday",
+                ) => Type::Int,
+                (Type::Duration, "seconds" | "millis") => Type::Int,
+                (Type::Command, "run") => Type::Compound(BTreeMap::from([
+                    ("status".to_string(), Type::Any),
+                    ("stdout".to_string(), Type::String),
+                    ("stderr".to_string(), Type::String),
+                ])),
+                (Type::List(_), "len") => Type::Int,
+                (Type::List(_), "is_empty") => Type::Bool,
+                (Type::List(_), "rest" | "push" | "slice") => t.clone(),
+                (Type::String, "slice") => Type::String,
+                (Type::Range, "len") => Type::Int,
+                (Type::Range, "is_empty") => Type::Bool,
+                (Type::Range, "rest") => Type::Range,
+                (Type::Range, "to_list") => Type::List(Box::new(Type::Int)),
+                (Type::List(_) | Type::Range, "stream") => Type::Stream,
+                (Type::Command, "lines") => Type::Stream,
+                (Type::Command, "on_exit") => Type::Unit,
+                (Type::Stream, "map" | "filter" | "take" | "chunk" | "dedupe") => Type::Stream,
+                (Type::Stream, "for_each") => Type::Unit,
+                (Type::Stream, "collect") => Type::List(Box::new(Type::Any)),
+                (Type::Task, "join") => Type::Any,
+                (Type::Task, "cancel") | (Type::Channel, "close") => Type::Unit,
+                (Type::Task, "is_done") => Type::Bool,
+                (Type::Channel, "stream") => Type::Stream,
+                (Type::None, _) if c.safe => Type::None,
+                (Type::Int | Type::Bool | Type::Unit | Type::None, name) => {
+                    self.error(format!("{t} has no property {name}"));
+                    Type::Any
+                }
+                _ => Type::Any,
+            };
+            child = c.child.as_deref();
+        }
+
+        match t {
+            Type::Fn { params, ret } if !args.is_empty() || params.is_empty() => {
+                if args.len() < params.len() {
+                    self.error(format!(
+                        "{} expects {} arguments but got {}",
+                        id.path,
+                        params.len(),
+                        args.len()
+                    ));
+                }
+                for (index, (arg, param)) in args.iter().zip(&params).enumerate() {
+                    self.expect(
+                        arg,
+                        param,
+                        &format!("argument {} of {}", index + 1, id.path),
+                    );
+                }
+                *ret
+            }
+            t => t,
+        }
+    }
+}
+
+/// The type of a `json"..."` literal, as precise as its contents allow.
+fn json_type(json: &serde_json::Value) -> Type {
+    match json {
+        serde_json::Value::Null => Type::None,
+        serde_json::Value::Bool(_) => Type::Bool,
+        serde_json::Value::Number(_) => Type::Int,
+        serde_json::Value::String(_) => Type::String,
+        serde_json::Value::Array(items) => Type::List(Box::new(
+            items
+                .iter()
+                .map(json_type)
+                .reduce(Type::join)
+                .unwrap_or(Type::Any),
+        )),
+        serde_json::Value::Object(fields) => Type::Compound(
+            fields
+                .iter()
+                .map(|(k, v)| (k.clone(), json_type(v)))
+                .collect(),
+        ),
+    }
+}
+
+#[test]
+fn test_check() {
+    let check = |source: &str| check(&crate::parser::parse_file(source).unwrap().1);
+
+    assert_eq!(check("let n = 1\nn + 2"), Ok(Type::Int));
+    assert!(check("let n = 1\nif n then {1} else {2}").is_err());
+    assert!(check("let s = \"a\"\n{ s * 2 }").is_err());
+    assert!(check("fn send(url) { http.post.json url \"hi\" }").is_err());
+    assert!(check("fn send(url) { http.post.json url (content=\"hi\") }").is_ok());
+    assert!(check("using line = fs.watch \"log\"\nline + 1").is_err());
+    assert!(check("let n: String = 1").is_err());
+    assert!(check("http.get url\"https://example.com\"").is_ok());
+    assert!(check("let p = json\"{\\\"n\\\": 1}\"\nlet n = p.n\nn + 1").is_ok());
+    assert!(check("let cmd: Url = sh\"ls\"").is_err());
+    assert!(check("let t = time.now\nlet d: Duration = t - (t - 5m)\nd * 2").is_ok());
+    assert!(check("let t = time.now\nt + 1").is_err());
+    assert!(check("let r = 0..10 --step 2\nlet n = r.len\nn + 1").is_ok());
+    assert!(check("0..\"a\"").is_err());
+    assert!(check("let lines = fs.watch \"log\"\nlet n: Int = lines.take 5").is_err());
+    assert!(check("let c = fs.cwd\nlet p: Path = c / \"a\"\nlet q = p.join \"b\"\nq / 1").is_err());
+    assert!(check("let n: Strnig = \"a\"").is_err());
+    assert!(check("fn f(dir) { dir / \"latest.log\" }").is_ok());
+    assert!(check("fn f(a) { \"x\" + a }").is_ok());
+    assert!(check("let c = chan\nlet t = spawn { send c 1 }\njoin [t]").is_ok());
+    assert!(check("let t = spawn { 1 }\nsend t 1").is_err());
+    assert!(check("every 1m { println \"tick\" }\nafter 5 { }").is_err());
+    assert!(check("fn every(n) { n * 2 }\nevery 3").is_ok());
+    assert!(check("fn throw(x) { x }\nthrow 1").is_ok());
+    assert!(check("let error = 1\nerror + 1").is_ok());
+    assert!(check("let batch = 3\nbatch + 1").is_ok());
+    assert!(check("[1] == [1]").is_ok());
+    assert!(check("[1] < [2]").is_err());
+    let send = "fn send(url: String, msg: (content: String, retries: Int?)) { msg.content }\n";
+    assert!(check(&format!("{send}send \"x\" (content=\"hi\", extra=1)")).is_ok());
+    assert!(check(&format!("{send}send \"x\" (text=\"hi\")")).is_err());
+    assert!(check(&format!("{send}send 1 (content=\"hi\")")).is_err());
+    assert!(check(&format!("{send}fn f(m) {{ send \"x\" m }}")).is_ok());
+}
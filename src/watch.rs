@@ -0,0 +1,585 @@
+use crate::interpreter::Error;
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// What `fs.watch` follows: a file, the files in a directory, or the files
+/// matching a glob such as `logs/**/*.log`.
+#[derive(Debug, Clone)]
+pub enum Target {
+    File(PathBuf),
+    Dir {
+        path: PathBuf,
+        recursive: bool,
+    },
+    Glob {
+        base: PathBuf,
+        pattern: regex::Regex,
+        /// Whether matches can be below the base directory's own files.
+        recursive: bool,
+    },
+}
+
+impl Target {
+    /// Relative paths are taken from the current directory. `recursive` makes
+    /// a directory include its subdirectories; globs use `**` for that.
+    pub fn new(path: &Path, recursive: bool) -> Result<Target, Error> {
+        let cwd = std::env::current_dir().map_err(|e| Error::new("io", e.to_string()))?;
+        let path = cwd.join(path);
+        let text = path.to_string_lossy();
+        if text.contains(['*', '?', '[']) {
+            let base = path
+                .ancestors()
+                .find(|p| !p.to_string_lossy().contains(['*', '?', '[']))
+                .unwrap_or(Path::new("/"))
+                .to_path_buf();
+            let pattern = glob_regex(&text)
+                .map_err(|e| Error::new("io", format!("invalid glob {text:?}: {e}")))?;
+            let rest = path.strip_prefix(&base).unwrap_or(&path).to_string_lossy();
+            let recursive = rest.contains("**") || rest.contains('/');
+            Ok(Target::Glob {
+                base,
+                pattern,
+                recursive,
+            })
+        } else if path.is_dir() {
+            Ok(Target::Dir { path, recursive })
+        } else {
+            Ok(Target::File(path))
+        }
+    }
+
+    fn matches(&self, file: &Path) -> bool {
+        match self {
+            Target::File(path) => file == path,
+            Target::Dir { path, recursive } => {
+                file.parent() == Some(path) || (*recursive && file.starts_with(path))
+            }
+            Target::Glob { pattern, .. } => pattern.is_match(&file.to_string_lossy()),
+        }
+    }
+
+    /// The directory to register with the watcher, and whether recursively.
+    /// Files are watched through their directory so that they can be created later.
+    fn root(&self) -> (&Path, bool) {
+        match self {
+            Target::File(path) => (path.parent().unwrap_or(Path::new("/")), false),
+            Target::Dir { path, recursive } => (path, *recursive),
+            Target::Glob {
+                base, recursive, ..
+            } => (base, *recursive),
+        }
+    }
+
+    /// The matching files that exist now.
+    fn existing(&self) -> Vec<PathBuf> {
+        match self {
+            Target::File(path) if path.is_file() => vec![path.clone()],
+            Target::File(_) => Vec::new(),
+            _ => {
+                let (root, recursive) = self.root();
+                let mut files = Vec::new();
+                list_files(root, recursive, &mut files);
+                files.retain(|f| self.matches(f));
+                files
+            }
+        }
+    }
+}
+
+fn list_files(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            if recursive {
+                list_files(&path, recursive, files);
+            }
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+/// Translates a glob into an anchored regex: `*` and `?` stay within one
+/// path component, `**` spans several, and `[...]` is a character class.
+fn glob_regex(glob: &str) -> Result<regex::Regex, regex::Error> {
+    let mut re = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `a/**/b` also matches `a/b`.
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    re.push_str("(?:.*/)?");
+                } else {
+                    re.push_str(".*");
+                }
+            }
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            '[' => {
+                re.push('[');
+                if chars.peek() == Some(&'!') {
+                    chars.next();
+                    re.push('^');
+                }
+                for c in chars.by_ref() {
+                    if c == ']' {
+                        break;
+                    }
+                    if c == '\\' || c == '[' {
+                        re.push('\\');
+                    }
+                    re.push(c);
+                }
+                re.push(']');
+            }
+            c => re.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    re.push('$');
+    regex::Regex::new(&re)
+}
+
+/// A line read from a watched file.
+pub struct Line {
+    pub text: String,
+    pub path: PathBuf,
+    /// Why the file was read: `modify` for lines appended to a file that was
+    /// already followed, `create` for a file that appeared, `rotate` for a
+    /// new file that replaced the followed one, and `truncate` for a file
+    /// that was cut short and is read again from its start.
+    pub kind: &'static str,
+}
+
+/// Identifies a file independently of its name, so that a log replaced by
+/// a new file of the same name can be told apart from it. Inode numbers are
+/// Unix only, as is following files by them.
+fn identity(meta: &std::fs::Metadata) -> (u64, u64) {
+    use std::os::unix::fs::MetadataExt;
+    (meta.dev(), meta.ino())
+}
+
+/// A followed file and the start of a line not yet terminated.
+struct Follow {
+    reader: BufReader<std::fs::File>,
+    id: (u64, u64),
+    partial: String,
+}
+
+impl Follow {
+    /// Opens the file at `path`, or gives `None` if there is none; it may
+    /// have been removed since it was seen.
+    fn open(path: &Path, from_end: bool) -> std::io::Result<Option<Follow>> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let id = identity(&file.metadata()?);
+        let mut reader = BufReader::new(file);
+        if from_end {
+            reader.seek(SeekFrom::End(0))?;
+        }
+        Ok(Some(Follow {
+            reader,
+            id,
+            partial: String::new(),
+        }))
+    }
+
+    /// The complete lines appended since the last read.
+    fn read_lines(&mut self) -> std::io::Result<Vec<String>> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(lines);
+            }
+            self.partial.push_str(&line);
+            if self.partial.ends_with('\n') {
+                let mut line = std::mem::take(&mut self.partial);
+                line.pop();
+                if line.ends_with('\r') {
+                    line.pop();
+                }
+                lines.push(line);
+            }
+        }
+    }
+}
+
+/// Catches up with whatever happened to the file at `path`, like `tail -F`:
+/// lines still in a followed file are read even if it was renamed away, and
+/// then a different file now at `path` is followed from its start.
+fn sync(
+    follows: &mut BTreeMap<PathBuf, Follow>,
+    path: &Path,
+) -> std::io::Result<Vec<(String, &'static str)>> {
+    let current = std::fs::metadata(path).ok().filter(|m| m.is_file());
+    let Some(follow) = follows.get_mut(path) else {
+        if current.is_none() {
+            return Ok(Vec::new());
+        }
+        let Some(mut follow) = Follow::open(path, false)? else {
+            return Ok(Vec::new());
+        };
+        let lines = follow.read_lines()?;
+        follows.insert(path.to_path_buf(), follow);
+        return Ok(lines.into_iter().map(|l| (l, "create")).collect());
+    };
+    let mut kind = "modify";
+    if let Some(meta) = &current {
+        if identity(meta) == follow.id && meta.len() < follow.reader.stream_position()? {
+            follow.reader.seek(SeekFrom::Start(0))?;
+            follow.partial.clear();
+            kind = "truncate";
+        }
+    }
+    let mut lines: Vec<_> = follow
+        .read_lines()?
+        .into_iter()
+        .map(|l| (l, kind))
+        .collect();
+    match current {
+        Some(meta) if identity(&meta) != follow.id => {
+            // If the new file is already gone again, its successor is waited for.
+            if let Some(new) = Follow::open(path, false)? {
+                *follow = new;
+                lines.extend(follow.read_lines()?.into_iter().map(|l| (l, "rotate")));
+            }
+        }
+        _ => {}
+    }
+    Ok(lines)
+}
+
+/// Where a followed file had been read up to, as kept in a `--state` file.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+struct Checkpoint {
+    dev: u64,
+    ino: u64,
+    offset: u64,
+    /// A hash of the file's first bytes, up to the offset. An inode number
+    /// can be reused by a new file once the old one is deleted, and this
+    /// tells them apart. Missing in state files from before it was added.
+    #[serde(default)]
+    head: Option<u64>,
+}
+
+/// How many bytes at the start of a file `Checkpoint::head` covers.
+const HEAD: u64 = 256;
+
+/// Hashes the first `min(len, HEAD)` bytes of `file` with FNV-1a, which,
+/// unlike the standard hasher, gives the same result in every build.
+/// `None` means the file is shorter than that now.
+fn head(file: &std::fs::File, len: u64) -> std::io::Result<Option<u64>> {
+    use std::os::unix::fs::FileExt;
+    let mut bytes = vec![0; len.min(HEAD) as usize];
+    match file.read_exact_at(&mut bytes, 0) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let hash = bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    });
+    Ok(Some(hash))
+}
+
+fn load_checkpoints(state: &Path) -> Result<BTreeMap<PathBuf, Checkpoint>, Error> {
+    match std::fs::read_to_string(state) {
+        Ok(json) => serde_json::from_str(&json)
+            .map_err(|e| Error::new("io", format!("{}: {e}", state.display()))),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(Error::new("io", format!("{}: {e}", state.display()))),
+    }
+}
+
+/// Resumes following `path` from its checkpoint. If the file was replaced
+/// meanwhile, the old one is looked for by identity in the same directory so
+/// that the rest of it is read before the new one.
+fn restore(path: &Path, saved: &Checkpoint) -> std::io::Result<Option<Follow>> {
+    let id = (saved.dev, saved.ino);
+    let current = std::fs::metadata(path).ok().filter(|m| m.is_file());
+    let candidates = std::iter::once(path.to_path_buf()).chain(
+        path.parent()
+            .and_then(|dir| std::fs::read_dir(dir).ok())
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|p| p != path),
+    );
+    for candidate in candidates {
+        if !std::fs::metadata(&candidate).is_ok_and(|m| m.is_file() && identity(&m) == id) {
+            continue;
+        }
+        let Some(mut follow) = Follow::open(&candidate, false)? else {
+            continue;
+        };
+        if follow.id != id {
+            continue;
+        }
+        let file = follow.reader.get_ref();
+        // A file shorter than the checkpoint was truncated and is read again.
+        if file.metadata()?.len() < saved.offset {
+            return Ok(Some(follow));
+        }
+        // A file that took over the old one's inode starts differently.
+        if saved.head.is_some() && head(file, saved.offset)? != saved.head {
+            continue;
+        }
+        follow.reader.seek(SeekFrom::Start(saved.offset))?;
+        return Ok(Some(follow));
+    }
+    // The old file is gone; the current one, if any, is read in full.
+    match current {
+        Some(_) => Follow::open(path, false),
+        None => Ok(None),
+    }
+}
+
+/// The files being followed and what to do with their lines.
+struct Follower {
+    targets: Vec<Target>,
+    follows: BTreeMap<PathBuf, Follow>,
+    emit: Box<dyn FnMut(Result<Line, Error>) -> bool + Send>,
+    /// Where to checkpoint the offsets, if anywhere.
+    state: Option<PathBuf>,
+    /// The checkpoints last loaded or saved, including ones for files this
+    /// watch doesn't follow.
+    checkpoints: BTreeMap<PathBuf, Checkpoint>,
+    open: bool,
+}
+
+impl Follower {
+    /// Reads and emits whatever is new in the files at `paths`, then
+    /// checkpoints the offsets if every line was handled.
+    fn process<'a>(&mut self, paths: impl Iterator<Item = &'a PathBuf>) {
+        for path in paths {
+            if !self.open {
+                return;
+            }
+            if !self.targets.iter().any(|t| t.matches(path)) {
+                continue;
+            }
+            let lines = match sync(&mut self.follows, path) {
+                Ok(lines) => lines,
+                Err(e) => {
+                    // The other files are still read if the error is handled.
+                    let message = format!("{}: {e}", path.display());
+                    self.open = (self.emit)(Err(Error::new("io", message)));
+                    continue;
+                }
+            };
+            // A named file stays followed while it is gone, in case it is
+            // being rotated; one matched by a directory or glob is dropped.
+            let named = self
+                .targets
+                .iter()
+                .any(|t| matches!(t, Target::File(file) if file == path));
+            if !named && !path.exists() {
+                self.follows.remove(path);
+            }
+            for (text, kind) in lines {
+                let line = Line {
+                    text,
+                    path: path.clone(),
+                    kind,
+                };
+                self.open = self.open && (self.emit)(Ok(line));
+            }
+        }
+        if self.open {
+            if let Err(e) = self.checkpoint() {
+                self.open = (self.emit)(Err(e));
+            }
+        }
+    }
+
+    /// Saves the offset of every followed file, up to its last complete line.
+    /// Checkpoints of files matching no target are kept, so that watches of
+    /// different files can share a state file, and so are ones of files
+    /// that may still come back.
+    fn checkpoint(&mut self) -> Result<(), Error> {
+        let Some(state) = &self.state else {
+            return Ok(());
+        };
+        let io = |e: std::io::Error| Error::new("io", format!("{}: {e}", state.display()));
+        let targets = &self.targets;
+        let checkpoints = &mut self.checkpoints;
+        checkpoints.retain(|path, _| !targets.iter().any(|t| t.matches(path)) || path.exists());
+        for (path, follow) in &mut self.follows {
+            let offset = follow.reader.stream_position().map_err(io)? - follow.partial.len() as u64;
+            let checkpoint = Checkpoint {
+                dev: follow.id.0,
+                ino: follow.id.1,
+                offset,
+                head: head(follow.reader.get_ref(), offset).map_err(io)?,
+            };
+            checkpoints.insert(path.clone(), checkpoint);
+        }
+        let json = serde_json::to_string_pretty(&*checkpoints).map_err(|e| e.to_string())?;
+        // Written aside and renamed, so that a crash never leaves half a file.
+        let mut temporary = state.clone().into_os_string();
+        temporary.push(".tmp");
+        std::fs::write(&temporary, json).map_err(io)?;
+        std::fs::rename(&temporary, state).map_err(io)
+    }
+}
+
+/// Starts following `targets`, passing each line appended to a matching
+/// file to `emit` until it returns false. Files that exist already are read
+/// from their end; a missing file is waited for. The watch lasts as long as
+/// the returned watcher.
+///
+/// With a `state` file, offsets are checkpointed there after every batch of
+/// lines `emit` accepted, and a restarted watch resumes from them.
+pub fn watch(
+    targets: Vec<Target>,
+    state: Option<PathBuf>,
+    emit: impl FnMut(Result<Line, Error>) -> bool + Send + 'static,
+) -> Result<Box<dyn Send>, Error> {
+    use notify::Watcher;
+
+    let checkpoints = match &state {
+        Some(state) => load_checkpoints(state)?,
+        None => BTreeMap::new(),
+    };
+    let io = |path: &Path, e: std::io::Error| Error::new("io", format!("{}: {e}", path.display()));
+    let mut follows = BTreeMap::new();
+    for target in &targets {
+        for path in target.existing() {
+            if let Some(follow) = Follow::open(&path, true).map_err(|e| io(&path, e))? {
+                follows.insert(path, follow);
+            }
+        }
+    }
+    // Checkpointed files are caught up with once the watch has started.
+    let mut resumed = Vec::new();
+    for (path, saved) in &checkpoints {
+        if !targets.iter().any(|t| t.matches(path)) {
+            continue;
+        }
+        if let Some(follow) = restore(path, saved).map_err(|e| io(path, e))? {
+            follows.insert(path.clone(), follow);
+            resumed.push(path.clone());
+        }
+    }
+
+    let follower = std::sync::Arc::new(std::sync::Mutex::new(Follower {
+        targets: targets.clone(),
+        follows,
+        emit: Box::new(emit),
+        state,
+        checkpoints,
+        open: true,
+    }));
+    let handler = {
+        let follower = follower.clone();
+        move |result: notify_debouncer_full::DebounceEventResult| match result {
+            Ok(events) => {
+                let paths = events.iter().flat_map(|event| &event.paths);
+                follower.lock().unwrap().process(paths);
+            }
+            Err(e) => eprintln!("watch error: {:?}", e),
+        }
+    };
+    let mut debouncer =
+        notify_debouncer_full::new_debouncer(std::time::Duration::from_secs(1), None, handler)
+            .map_err(|e| e.to_string())?;
+    let mut roots: Vec<(&Path, bool)> = targets.iter().map(Target::root).collect();
+    roots.sort();
+    roots.dedup();
+    for (root, recursive) in roots {
+        let mode = if recursive {
+            notify::RecursiveMode::Recursive
+        } else {
+            notify::RecursiveMode::NonRecursive
+        };
+        debouncer
+            .watcher()
+            .watch(root, mode)
+            .map_err(|e| Error::new("io", format!("{}: {e}", root.display())))?;
+    }
+    if !resumed.is_empty() {
+        // `emit` may wait for the script, which only runs once this returns.
+        std::thread::spawn(move || follower.lock().unwrap().process(resumed.iter()));
+    }
+    Ok(Box::new(debouncer))
+}
+
+#[test]
+fn test_targets() {
+    let glob = Target::new(Path::new("/var/log/**/*.log"), false).unwrap();
+    assert_eq!(glob.root(), (Path::new("/var/log"), true));
+    assert!(glob.matches(Path::new("/var/log/app.log")));
+    assert!(glob.matches(Path::new("/var/log/nginx/access.log")));
+    assert!(!glob.matches(Path::new("/var/log/app.log.1")));
+    let glob = Target::new(Path::new("/var/log/app-?.[0-9]"), false).unwrap();
+    assert_eq!(glob.root(), (Path::new("/var/log"), false));
+    assert!(glob.matches(Path::new("/var/log/app-a.1")));
+    assert!(!glob.matches(Path::new("/var/log/app-a.x")));
+    let file = Target::new(Path::new("/var/log/latest.log"), false).unwrap();
+    assert_eq!(file.root(), (Path::new("/var/log"), false));
+    assert!(!file.matches(Path::new("/var/log/other.log")));
+}
+
+#[test]
+fn test_restore() {
+    let tmp = std::env::temp_dir().join(format!("haksh-restore-{}", std::process::id()));
+    std::fs::create_dir_all(&tmp).unwrap();
+    let path = tmp.join("app.log");
+    std::fs::write(&path, "one\ntwo\n").unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+    let (dev, ino) = identity(&file.metadata().unwrap());
+    let checkpoint = |head| Checkpoint {
+        dev,
+        ino,
+        offset: 4,
+        head,
+    };
+    let rest = |saved: &Checkpoint| {
+        restore(&path, saved)
+            .unwrap()
+            .unwrap()
+            .read_lines()
+            .unwrap()
+    };
+    assert_eq!(rest(&checkpoint(head(&file, 4).unwrap())), ["two"]);
+    assert_eq!(rest(&checkpoint(None)), ["two"]);
+    // The same inode with different contents is a new file, read in full.
+    assert_eq!(rest(&checkpoint(Some(0))), ["one", "two"]);
+
+    // Checkpoints of files the watch doesn't follow are kept.
+    let state = tmp.join("state.json");
+    let elsewhere = tmp.join("other").join("x.log");
+    let mut follower = Follower {
+        targets: vec![Target::new(&path, false).unwrap()],
+        follows: BTreeMap::from([(path.clone(), Follow::open(&path, false).unwrap().unwrap())]),
+        emit: Box::new(|_| true),
+        state: Some(state.clone()),
+        checkpoints: BTreeMap::from([(elsewhere.clone(), checkpoint(None))]),
+        open: true,
+    };
+    follower.checkpoint().unwrap();
+    let saved = load_checkpoints(&state).unwrap();
+    std::fs::remove_dir_all(&tmp).unwrap();
+    assert_eq!(saved[&elsewhere], checkpoint(None));
+    assert_eq!(saved[&path].offset, 0);
+}
+
+#[test]
+fn test_sync_missing() {
+    let path = std::env::temp_dir().join(format!("haksh-missing-{}.log", std::process::id()));
+    assert!(Follow::open(&path, false).unwrap().is_none());
+    let mut follows = BTreeMap::new();
+    assert!(sync(&mut follows, &path).unwrap().is_empty());
+    assert!(follows.is_empty());
+}